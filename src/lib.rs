@@ -1,3 +1,4 @@
 pub mod signals;
+pub mod state;
 pub mod z80;
 pub mod zxspectrum;
@@ -1,6 +1,10 @@
 use b2t80s_rust::zxspectrum::{
-    ula::{SCREEN_HEIGHT, SCREEN_WIDTH, SRC_SIZE},
-    zx48k::{MachineMessage, UICommands, Zx48k},
+    headless,
+    machine::{load_snapshot_file, save_screenshot_file, save_snapshot_file, MachineMessage},
+    screenshot::ShotOptions,
+    ula::{self, FrameSink, KeyChange, SCREEN_HEIGHT, SCREEN_WIDTH, SRC_SIZE},
+    zx128k::Zx128k,
+    zx48k::Zx48k,
 };
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
@@ -9,16 +13,18 @@ use cpal::{
 use iced::{
     event,
     futures::{
-        channel::mpsc::{self, channel, Sender},
+        channel::mpsc::{self, Sender},
         SinkExt, StreamExt,
     },
-    keyboard::Event as KeyEvent,
+    keyboard::{key::Named, Event as KeyEvent, Key},
     subscription,
     widget::{button, column, container, image, row, slider, text, tooltip, Image},
     Alignment, Command, ContentFit, Element, Event, Length, Subscription,
 };
 use std::{
-    panic, process,
+    env, panic,
+    path::PathBuf,
+    process,
     sync::{Arc, Mutex},
     time::Instant,
 };
@@ -33,11 +39,92 @@ fn main() -> iced::Result {
         process::exit(1);
     }));
 
-    iced::program("ZX Spectrum 48K", UI::update, UI::view)
+    if env::args().any(|arg| arg == "--headless") {
+        process::exit(headless::main(env::args().skip(1)));
+    }
+
+    let title = if is_128k() {
+        "ZX Spectrum 128K"
+    } else {
+        "ZX Spectrum 48K"
+    };
+
+    iced::program(title, UI::update, UI::view)
         .subscription(UI::subscription)
         .run()
 }
 
+fn is_128k() -> bool {
+    env::args().any(|arg| arg == "--128k")
+}
+
+/// Snapshot to start from, the first argument that is not an option.
+fn snapshot_arg() -> Option<PathBuf> {
+    env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with("--"))
+        .map(PathBuf::from)
+}
+
+fn is_tape_auto_start() -> bool {
+    env::args().any(|arg| arg == "--tape-autostart")
+}
+
+/// Seconds the "Back" button rewinds.
+const REWIND_SECONDS: u32 = 5;
+
+/// F1-F4 quick-load a slot, Shift+F1-F4 quick-save it. Returns (slot, save).
+fn quick_slot(event: &KeyEvent) -> Option<(usize, bool)> {
+    let KeyEvent::KeyPressed {
+        key: Key::Named(key),
+        modifiers,
+        ..
+    } = event
+    else {
+        return None;
+    };
+    let slot = match key {
+        Named::F1 => 0,
+        Named::F2 => 1,
+        Named::F3 => 2,
+        Named::F4 => 3,
+        _ => return None,
+    };
+    Some((slot, modifiers.shift()))
+}
+
+/// The Spectrum keys a key of the PC stands for, by name; the arrows and backspace are
+/// shifted 5-8 and 0.
+fn spectrum_keys(key: &Key) -> Vec<(usize, usize)> {
+    let names = match key.as_ref() {
+        Key::Named(Named::Enter) => vec!["enter"],
+        Key::Named(Named::Shift) => vec!["caps"],
+        Key::Named(Named::Space) => vec!["space"],
+        Key::Named(Named::Alt) => vec!["sym"],
+        Key::Named(Named::ArrowUp) => vec!["caps", "7"],
+        Key::Named(Named::ArrowDown) => vec!["caps", "6"],
+        Key::Named(Named::ArrowLeft) => vec!["caps", "5"],
+        Key::Named(Named::ArrowRight) => vec!["caps", "8"],
+        Key::Named(Named::Backspace) => vec!["caps", "0"],
+        Key::Character(c) => vec![c],
+        _ => vec![],
+    };
+    names.into_iter().filter_map(ula::key).collect()
+}
+
+/// Key changes for the ULA of a keyboard event.
+fn key_changes(event: &KeyEvent) -> Vec<KeyChange> {
+    let (key, pressed) = match event {
+        KeyEvent::KeyPressed { key, .. } => (key, true),
+        KeyEvent::KeyReleased { key, .. } => (key, false),
+        KeyEvent::ModifiersChanged(_) => return vec![],
+    };
+    spectrum_keys(key)
+        .into_iter()
+        .map(|(row, bit)| KeyChange { row, bit, pressed })
+        .collect()
+}
+
 /* ********************************************* */
 
 #[derive(Debug, Clone)]
@@ -47,6 +134,52 @@ enum Message {
     SetBuffer(usize),
     KeyEvent(KeyEvent),
     SetVolume(f32),
+    TapePlay,
+    TapeStop,
+    TapeRewind,
+    ToggleFastLoad,
+    SnapshotLoad,
+    SnapshotSave,
+    Screenshot,
+    ToggleShotMode,
+    Rewind,
+    Nmi,
+}
+
+/// What the machine thread asks of the UI.
+#[derive(Debug)]
+enum UICommands {
+    DrawBuffer(usize),
+}
+
+/// Copies the frames into two shared bitmaps in turn and tells the UI which one to draw.
+struct BitmapSink {
+    bitmaps: [Arc<Mutex<Vec<u8>>>; 2],
+    buffer: usize,
+    ui_ctl_tx: Sender<UICommands>,
+}
+
+impl BitmapSink {
+    fn new(bitmaps: [Arc<Mutex<Vec<u8>>>; 2], ui_ctl_tx: Sender<UICommands>) -> Self {
+        Self {
+            bitmaps,
+            buffer: 0,
+            ui_ctl_tx,
+        }
+    }
+}
+
+impl FrameSink for BitmapSink {
+    fn frame(&mut self, pixels: &[u8]) {
+        self.bitmaps[self.buffer]
+            .lock()
+            .unwrap()
+            .copy_from_slice(pixels);
+        self.ui_ctl_tx
+            .start_send(UICommands::DrawBuffer(self.buffer))
+            .unwrap();
+        self.buffer = 1 - self.buffer;
+    }
 }
 
 enum State {
@@ -57,11 +190,13 @@ enum State {
 struct UI {
     bitmaps: [Arc<Mutex<Vec<u8>>>; 2],
     buffer: usize,
-    machine_ctl_tx: Option<Sender<MachineMessage>>,
-    event_tx: Option<Sender<KeyEvent>>,
+    machine_ctl_tx: Option<std::sync::mpsc::Sender<MachineMessage>>,
+    event_tx: Option<std::sync::mpsc::Sender<KeyChange>>,
     fps: FPSCounter,
     stream: Option<Stream>,
     volume: Arc<Mutex<f32>>,
+    fast_load: bool,
+    shot: ShotOptions,
 }
 
 struct FPSCounter {
@@ -108,6 +243,8 @@ impl Default for UI {
             fps: FPSCounter::new(),
             stream: None,
             volume: Arc::new(Mutex::new(0.5)),
+            fast_load: true,
+            shot: ShotOptions::default(),
         }
     }
 }
@@ -116,8 +253,8 @@ impl UI {
     pub fn update(&mut self, msg: Message) -> Command<Message> {
         match (msg, self.event_tx.as_mut()) {
             (Message::Ready(sender), _) => {
-                let (event_tx, event_rx) = channel::<KeyEvent>(10);
-                let (machine_ctl_tx, machine_ctl_rx) = channel::<MachineMessage>(0);
+                let (event_tx, event_rx) = std::sync::mpsc::channel();
+                let (machine_ctl_tx, machine_ctl_rx) = std::sync::mpsc::channel();
 
                 let (stream, sound_tx) = match SoundEngine::init_engine(self.volume.clone()) {
                     Ok((stream, sound_tx)) => match stream.play() {
@@ -128,21 +265,49 @@ impl UI {
                 };
                 self.stream = Some(stream);
 
-                let mut zx = Zx48k::new(
-                    [self.bitmaps[0].clone(), self.bitmaps[1].clone()],
-                    event_rx,
-                    machine_ctl_rx,
-                    machine_ctl_tx.clone(),
-                    sender.clone(),
-                    sound_tx,
-                );
+                let bitmaps = [self.bitmaps[0].clone(), self.bitmaps[1].clone()];
+                let frames = Box::new(BitmapSink::new(bitmaps, sender.clone()));
+                if is_128k() {
+                    let mut zx = Zx128k::new(
+                        frames,
+                        event_rx,
+                        machine_ctl_rx,
+                        machine_ctl_tx.clone(),
+                        Some(sound_tx),
+                    );
+                    zx.set_tape_auto_start(is_tape_auto_start());
+                    zx.set_tape_fast_load(self.fast_load);
+                    if let Some(path) = snapshot_arg() {
+                        if let Err(e) = zx.load_snapshot(&path) {
+                            println!("Error loading snapshot: {}", e);
+                        }
+                    }
+                    task::spawn(async move {
+                        zx.run().await;
+                    });
+                } else {
+                    let mut zx = Zx48k::new(
+                        frames,
+                        event_rx,
+                        machine_ctl_rx,
+                        machine_ctl_tx.clone(),
+                        Some(sound_tx),
+                    );
+                    zx.set_melodik(env::args().any(|arg| arg == "--melodik"));
+                    zx.set_tape_auto_start(is_tape_auto_start());
+                    zx.set_tape_fast_load(self.fast_load);
+                    if let Some(path) = snapshot_arg() {
+                        if let Err(e) = zx.load_snapshot(&path) {
+                            println!("Error loading snapshot: {}", e);
+                        }
+                    }
+                    task::spawn(async move {
+                        zx.run().await;
+                    });
+                }
 
                 self.machine_ctl_tx = Some(machine_ctl_tx.clone());
                 self.event_tx = Some(event_tx.clone());
-
-                task::spawn(async move {
-                    zx.run().await;
-                });
             }
             (Message::SetBuffer(b), _) => {
                 self.buffer = b;
@@ -152,13 +317,65 @@ impl UI {
                 *self.volume.lock().unwrap() = b;
                 println!("SetVolume: {}", b);
             }
-            (Message::KeyEvent(e), Some(tx)) => tx.start_send(e).unwrap(),
+            (Message::TapePlay, _) => self.send_machine(MachineMessage::TapePlay),
+            (Message::TapeStop, _) => self.send_machine(MachineMessage::TapeStop),
+            (Message::TapeRewind, _) => self.send_machine(MachineMessage::TapeRewind),
+            (Message::ToggleFastLoad, _) => {
+                self.fast_load = !self.fast_load;
+                self.send_machine(MachineMessage::TapeFastLoad(self.fast_load));
+            }
+            (Message::SnapshotLoad, _) => {
+                if let Some(tx) = self.machine_ctl_tx.as_ref() {
+                    load_snapshot_file(tx.clone());
+                }
+            }
+            (Message::SnapshotSave, _) => {
+                if let Some(tx) = self.machine_ctl_tx.as_ref() {
+                    save_snapshot_file(tx.clone());
+                }
+            }
+            (Message::Screenshot, _) => {
+                if let Some(tx) = self.machine_ctl_tx.as_ref() {
+                    save_screenshot_file(tx.clone(), self.shot);
+                }
+            }
+            (Message::ToggleShotMode, _) => {
+                // 1x, 2x, then the same without the border
+                self.shot.scale = 3 - self.shot.scale;
+                if self.shot.scale == 1 {
+                    self.shot.border = !self.shot.border;
+                }
+            }
+            (Message::Rewind, _) => self.send_machine(MachineMessage::Rewind(REWIND_SECONDS)),
+            (Message::Nmi, _) => self.send_machine(MachineMessage::Nmi),
+            (Message::KeyEvent(e), _) if quick_slot(&e).is_some() => {
+                if let Some((slot, save)) = quick_slot(&e) {
+                    self.send_machine(if save {
+                        MachineMessage::QuickSave(slot)
+                    } else {
+                        MachineMessage::QuickLoad(slot)
+                    });
+                }
+            }
+            (Message::KeyEvent(e), Some(tx)) => {
+                for change in key_changes(&e) {
+                    let _ = tx.send(change);
+                }
+            }
             _ => (),
         }
 
         Command::none()
     }
 
+    fn send_machine(&mut self, msg: MachineMessage) {
+        if let Some(tx) = self.machine_ctl_tx.as_ref() {
+            if let Err(e) = tx.send(msg) {
+                println!("send error: {}", e);
+            }
+        }
+    }
+
     pub fn view(&self) -> Element<'_, Message> {
         let screen = image::Handle::from_rgba(
             SCREEN_WIDTH as u32,
@@ -174,6 +391,32 @@ impl UI {
 
         let controls = row![
             action(text("Reset"), "Reset", None),
+            action(text("NMI"), "Non-maskable interrupt", Some(Message::Nmi)),
+            action(text("Load"), "Load snapshot", Some(Message::SnapshotLoad)),
+            action(text("Save"), "Save snapshot", Some(Message::SnapshotSave)),
+            action(text("Shot"), "Save a screenshot", Some(Message::Screenshot)),
+            action(
+                text(format!(
+                    "Shot: {}x{}",
+                    self.shot.scale,
+                    if self.shot.border { "" } else { ", no border" }
+                )),
+                "Screenshot scale and border",
+                Some(Message::ToggleShotMode)
+            ),
+            action(text("Back"), "Rewind 5 seconds", Some(Message::Rewind)),
+            action(text("Play"), "Play tape", Some(Message::TapePlay)),
+            action(text("Stop"), "Stop tape", Some(Message::TapeStop)),
+            action(text("Rewind"), "Rewind tape", Some(Message::TapeRewind)),
+            action(
+                text(if self.fast_load {
+                    "Fast load: on"
+                } else {
+                    "Fast load: off"
+                }),
+                "Run flat out while loading",
+                Some(Message::ToggleFastLoad)
+            ),
             text("Volume"),
             slider::Slider::new(0.0..=1.0, *self.volume.lock().unwrap(), Message::SetVolume)
                 .step(0.1)
@@ -1,7 +1,13 @@
+use std::io::Error;
+
+use crate::state::{invalid_state, SaveState, StateReader, StateWriter};
+
 #[derive(Default, Debug)]
 pub enum SignalReq {
     Read,
     Write,
+    /// Interrupt acknowledge (M1 with IORQ), only on `port`.
+    Ack,
     #[default]
     None,
 }
@@ -13,4 +19,48 @@ pub struct Signals {
     pub mem: SignalReq,
     pub port: SignalReq,
     pub interrupt: bool,
+    /// Level of the NMI line, the CPU reacts to its rising edge.
+    pub nmi: bool,
+}
+
+impl SignalReq {
+    fn to_u8(&self) -> u8 {
+        match self {
+            SignalReq::Read => 1,
+            SignalReq::Write => 2,
+            SignalReq::Ack => 3,
+            SignalReq::None => 0,
+        }
+    }
+
+    fn from_u8(v: u8) -> Result<Self, Error> {
+        match v {
+            0 => Ok(SignalReq::None),
+            1 => Ok(SignalReq::Read),
+            2 => Ok(SignalReq::Write),
+            3 => Ok(SignalReq::Ack),
+            _ => Err(invalid_state("invalid signal request")),
+        }
+    }
+}
+
+impl SaveState for Signals {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.addr);
+        w.u8(self.data);
+        w.u8(self.mem.to_u8());
+        w.u8(self.port.to_u8());
+        w.bool(self.interrupt);
+        w.bool(self.nmi);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.addr = r.u16()?;
+        self.data = r.u8()?;
+        self.mem = SignalReq::from_u8(r.u8()?)?;
+        self.port = SignalReq::from_u8(r.u8()?)?;
+        self.interrupt = r.bool()?;
+        self.nmi = r.bool()?;
+        Ok(())
+    }
 }
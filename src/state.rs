@@ -0,0 +1,163 @@
+use std::io::{Error, ErrorKind};
+
+const MAGIC: &[u8; 4] = b"B2TS";
+/// Bumped whenever a component changes what it saves, older states are rejected.
+pub const STATE_VERSION: u8 = 9;
+
+pub const MACHINE_48K: u8 = 0;
+pub const MACHINE_128K: u8 = 1;
+
+/// Number of quick-save slots of a machine.
+pub const QUICK_SLOTS: usize = 4;
+
+/// Components that can be saved to, and restored from, a save state.
+pub trait SaveState {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error>;
+}
+
+/// Little endian binary blob of a save state.
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Magic, format version and machine the state belongs to.
+    pub fn header(&mut self, machine: u8) {
+        self.raw(MAGIC);
+        self.u8(STATE_VERSION);
+        self.u8(machine);
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.data.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.data.push(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, v: f32) {
+        self.u32(v.to_bits());
+    }
+
+    pub fn opt_u8(&mut self, v: Option<u8>) {
+        self.bool(v.is_some());
+        self.u8(v.unwrap_or_default());
+    }
+
+    pub fn opt_u16(&mut self, v: Option<u16>) {
+        self.bool(v.is_some());
+        self.u16(v.unwrap_or_default());
+    }
+
+    /// Fixed size data, the reader must know the length.
+    pub fn raw(&mut self, v: &[u8]) {
+        self.data.extend_from_slice(v);
+    }
+
+    /// Variable size data, prefixed with its length.
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.raw(v);
+    }
+
+    pub fn str(&mut self, v: &str) {
+        self.bytes(v.as_bytes());
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn header(&mut self, machine: u8) -> Result<(), Error> {
+        if self.raw(MAGIC.len())? != MAGIC {
+            return Err(invalid_state("not a save state"));
+        }
+        if self.u8()? != STATE_VERSION {
+            return Err(invalid_state("unsupported save state version"));
+        }
+        if self.u8()? != machine {
+            return Err(invalid_state("save state of another machine"));
+        }
+        Ok(())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.raw(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Error> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.raw(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.raw(4)?.try_into().unwrap()))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, Error> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    pub fn opt_u8(&mut self) -> Result<Option<u8>, Error> {
+        let some = self.bool()?;
+        let v = self.u8()?;
+        Ok(some.then_some(v))
+    }
+
+    pub fn opt_u16(&mut self) -> Result<Option<u16>, Error> {
+        let some = self.bool()?;
+        let v = self.u16()?;
+        Ok(some.then_some(v))
+    }
+
+    pub fn raw(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let data = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| invalid_state("truncated save state"))?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u32()? as usize;
+        self.raw(len)
+    }
+
+    pub fn str(&mut self) -> Result<String, Error> {
+        Ok(String::from_utf8_lossy(self.bytes()?).to_string())
+    }
+}
+
+pub fn invalid_state(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
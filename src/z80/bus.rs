@@ -0,0 +1,65 @@
+/// Memory and IO map driven by the `CPU` through `CPU::tick_with`.
+///
+/// Hosts only implement the accesses; the signal polling is done by the CPU.
+pub trait Bus {
+    fn mem_read(&mut self, addr: u16) -> u8;
+    fn mem_write(&mut self, addr: u16, data: u8);
+    fn io_read(&mut self, port: u16) -> u8;
+    fn io_write(&mut self, port: u16, data: u8);
+
+    /// State of the INT line.
+    fn int(&mut self) -> bool {
+        false
+    }
+
+    /// Byte on the data bus during the interrupt acknowledge cycle: the vector of a
+    /// peripheral, or whatever the bus floats to. IM 0 executes it, IM 2 uses it as the low
+    /// byte of the vector address.
+    fn int_ack(&mut self) -> u8 {
+        0xff
+    }
+
+    /// State of the NMI line.
+    fn nmi(&mut self) -> bool {
+        false
+    }
+
+    /// Called once per T-state, before the CPU advances (even when it is held by `wait`).
+    fn tick(&mut self) {}
+
+    /// State of the WAIT line before the CPU runs `access`: while `true` the CPU does not
+    /// advance, used to model contention. Internal T-states come as `t` 1 on the address the
+    /// CPU leaves on the bus, `None` only while it acknowledges an interrupt.
+    fn wait(&mut self, _access: Option<Access>) -> bool {
+        false
+    }
+}
+
+/// T-state of an M-cycle the CPU is about to run, `t` counts from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// Opcode fetch, memory read or write.
+    Mem {
+        addr: u16,
+        t: u8,
+    },
+    Io {
+        port: u16,
+        t: u8,
+    },
+}
+
+/// State of the bus during one T-state, recorded by `CPU::trace`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BusCycle {
+    /// T-state since the trace was started.
+    pub t: usize,
+    pub addr: u16,
+    pub data: u8,
+    pub mreq: bool,
+    pub iorq: bool,
+    pub rd: bool,
+    pub wr: bool,
+    pub m1: bool,
+    pub rfsh: bool,
+}
@@ -1,6 +1,10 @@
+use std::io::Error;
+
 use crate::signals::{SignalReq, Signals};
+use crate::state::{invalid_state, SaveState, StateReader, StateWriter};
 
 use super::{
+    bus::{Access, Bus, BusCycle},
     diss::disassemble,
     ops_codes::*,
     registers::{IndexMode, Registers},
@@ -14,9 +18,15 @@ pub struct CPU {
     pub wait: bool,
     pub halt: bool,
     pub do_reset: bool,
+    /// A rising edge was seen on the NMI line, taken at the next instruction boundary.
+    pub nmi_pending: bool,
+    /// Set by EI, no maskable interrupt is accepted before the next instruction.
+    pub ei_shadow: bool,
     pub current_ops: Option<Operation>,
     pub current_ops_ts: u8,
     pub log: Vec<String>,
+    /// When set, the state of the bus is appended every T-state driven by `tick_with`.
+    pub trace: Option<Vec<BusCycle>>,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -54,12 +64,14 @@ pub enum Operation {
     Mw16(u16, u16),
     MrAddrN(u16),
     MrAddrR(u16, u8),
-    Delay(u8),
+    /// Internal T-states, and the address the CPU leaves on the bus meanwhile.
+    Delay(u8, u16),
     Pw8(u16, u8),
     PrR(u16, Option<u8>, bool),
     MrPcD,
-    Int01,
-    Int02,
+    /// Interrupt acknowledge: an M1 cycle that reads the data bus instead of memory.
+    IntAck,
+    Nmi,
 }
 
 impl CPU {
@@ -70,17 +82,21 @@ impl CPU {
                 addr: 0,
                 data: 0,
                 interrupt: false,
+                nmi: false,
                 mem: SignalReq::None,
                 port: SignalReq::None,
             },
             fetched: Fetched::new(0),
             scheduler: Vec::new(),
             do_reset: false,
+            nmi_pending: false,
+            ei_shadow: false,
             wait: false,
             halt: false,
             current_ops: Some(Operation::Fetch),
             current_ops_ts: 0,
             log: Vec::new(),
+            trace: None,
         }
     }
 
@@ -89,15 +105,6 @@ impl CPU {
             return None;
         }
 
-        if self.halt {
-            if self.signals.interrupt {
-                self.halt = false;
-                self.regs.pc += 1;
-            } else {
-                return None;
-            }
-        }
-
         if matches!(self.current_ops, None) {
             if self.scheduler.is_empty() {
                 self.log.push(disassemble(self.fetched));
@@ -107,6 +114,7 @@ impl CPU {
 
                 self.fetched = Fetched::new(self.regs.pc);
                 self.regs.index_mode = IndexMode::Hl;
+                let ei_shadow = std::mem::take(&mut self.ei_shadow);
 
                 if self.do_reset {
                     println!("Reset");
@@ -121,12 +129,13 @@ impl CPU {
                     self.halt = false;
                     self.do_reset = false;
                     return None;
-                } else if self.signals.interrupt && self.regs.iff1 {
-                    match self.regs.im {
-                        0 | 1 => self.current_ops = Some(Operation::Int01),
-                        2 => self.current_ops = Some(Operation::Int02),
-                        _ => unreachable!("Invalid interrupt mode"),
-                    }
+                } else if self.nmi_pending {
+                    self.nmi_pending = false;
+                    self.halt = false;
+                    self.current_ops = Some(Operation::Nmi);
+                } else if self.signals.interrupt && self.regs.iff1 && !ei_shadow {
+                    self.halt = false;
+                    self.current_ops = Some(Operation::IntAck);
                 } else {
                     self.current_ops = Some(Operation::Fetch);
                 }
@@ -135,45 +144,162 @@ impl CPU {
             }
         }
 
-        let c = self.current_ops;
-        match c {
-            Some(op) => {
-                // println!("{}: {:?}", self.current_ops_ts, op);
-                let done = match op {
-                    Operation::Fetch => self.fetch(),
-                    Operation::MrPcN => self.mr(),
-                    Operation::MrPcD => self.mr_d(),
-                    Operation::Mw8(addr, data) => self.mw_8(addr, data),
-                    Operation::Mw16(addr, data) => self.mw_16(addr, data),
-                    Operation::MrAddrN(addr) => self.mr_addr_n(addr),
-                    Operation::MrAddrR(addr, r) => self.mr_addr_r(addr, r),
-                    Operation::Delay(delay) => self.delay(delay),
-                    Operation::Pw8(addr, data) => self.pw_8(addr, data),
-                    Operation::PrR(addr, r, flags) => self.pr_r(addr, r, flags),
-                    Operation::Int01 => self.int01(),
-                    Operation::Int02 => self.int02(),
-                };
-                if done {
-                    // println!(
-                    //     "-- done -- op: {:?} - {}",
-                    //     self.current_ops, self.current_ops_ts
-                    // );
-                    self.current_ops = None;
-                    self.current_ops_ts = 0;
-                    if self.scheduler.is_empty() {
-                        self.decode_and_run();
-                    }
+        if let Some(op) = self.current_ops {
+            // println!("{}: {:?}", self.current_ops_ts, op);
+            let done = match op {
+                Operation::Fetch => self.fetch(),
+                Operation::MrPcN => self.mr(),
+                Operation::MrPcD => self.mr_d(),
+                Operation::Mw8(addr, data) => self.mw_8(addr, data),
+                Operation::Mw16(addr, data) => self.mw_16(addr, data),
+                Operation::MrAddrN(addr) => self.mr_addr_n(addr),
+                Operation::MrAddrR(addr, r) => self.mr_addr_r(addr, r),
+                Operation::Delay(delay, _) => self.delay(delay),
+                Operation::Pw8(addr, data) => self.pw_8(addr, data),
+                Operation::PrR(addr, r, flags) => self.pr_r(addr, r, flags),
+                Operation::IntAck => self.int_ack(),
+                Operation::Nmi => self.nmi(),
+            };
+            if done {
+                // println!(
+                //     "-- done -- op: {:?} - {}",
+                //     self.current_ops, self.current_ops_ts
+                // );
+                self.current_ops = None;
+                self.current_ops_ts = 0;
+                if self.scheduler.is_empty() {
+                    self.decode_and_run();
                 }
             }
-            None => todo!(),
-        };
+        }
 
         if matches!(self.current_ops, None) && self.scheduler.is_empty() {
+            self.regs.q = if sets_flags(self.fetched.prefix, self.fetched.op_code) {
+                self.regs.f.get()
+            } else {
+                0
+            };
             return Some(self.regs.pc);
         }
         None
     }
 
+    /// Pulses the NMI line, for hosts that do not drive it through `Bus::nmi` (an NMI button).
+    pub fn pulse_nmi(&mut self) {
+        self.nmi_pending = true;
+    }
+
+    /// Replaces the registers, dropping the instruction in progress.
+    pub fn set_registers(&mut self, regs: Registers) {
+        self.regs = regs;
+        self.scheduler.clear();
+        self.current_ops = None;
+        self.current_ops_ts = 0;
+        self.signals.mem = SignalReq::None;
+        self.signals.port = SignalReq::None;
+        self.halt = false;
+        self.do_reset = false;
+        self.nmi_pending = false;
+        self.ei_shadow = false;
+    }
+
+    /// Runs one T-state against `bus`, serving the pending memory/IO request first.
+    /// Returns the same instruction-boundary value as `tick`.
+    pub fn tick_with(&mut self, bus: &mut impl Bus) -> Option<u16> {
+        bus.tick();
+
+        match self.signals.mem {
+            SignalReq::Read => self.signals.data = bus.mem_read(self.signals.addr),
+            SignalReq::Write => bus.mem_write(self.signals.addr, self.signals.data),
+            SignalReq::Ack | SignalReq::None => (),
+        }
+        match self.signals.port {
+            SignalReq::Read => self.signals.data = bus.io_read(self.signals.addr),
+            SignalReq::Write => bus.io_write(self.signals.addr, self.signals.data),
+            SignalReq::Ack => self.signals.data = bus.int_ack(),
+            SignalReq::None => (),
+        }
+        self.signals.interrupt = bus.int();
+        let nmi = bus.nmi();
+        if nmi && !self.signals.nmi {
+            self.nmi_pending = true;
+        }
+        self.signals.nmi = nmi;
+
+        self.trace_cycle();
+        if bus.wait(self.next_access()) {
+            return None;
+        }
+        self.tick()
+    }
+
+    /// The bus access of the T-state `tick` runs next.
+    fn next_access(&self) -> Option<Access> {
+        let (op, t) = match self.current_ops {
+            Some(op) => (op, self.current_ops_ts + 1),
+            None => match self.scheduler.first() {
+                Some(op) => (*op, 1),
+                None if self.do_reset => return None,
+                None if self.nmi_pending => (Operation::Nmi, 1),
+                None if self.signals.interrupt && self.regs.iff1 && !self.ei_shadow => {
+                    (Operation::IntAck, 1)
+                }
+                None => (Operation::Fetch, 1),
+            },
+        };
+        match op {
+            Operation::Fetch | Operation::Nmi | Operation::MrPcN | Operation::MrPcD => {
+                Some(Access::Mem {
+                    addr: self.regs.pc,
+                    t,
+                })
+            }
+            Operation::MrAddrN(addr) | Operation::MrAddrR(addr, _) | Operation::Mw8(addr, _) => {
+                Some(Access::Mem { addr, t })
+            }
+            Operation::Mw16(addr, _) if t <= 3 => Some(Access::Mem { addr, t }),
+            Operation::Mw16(addr, _) => Some(Access::Mem {
+                addr: addr.wrapping_add(1),
+                t: t - 3,
+            }),
+            Operation::PrR(port, _, _) | Operation::Pw8(port, _) => Some(Access::Io { port, t }),
+            // every internal T-state can be held on its own
+            Operation::Delay(_, addr) => Some(Access::Mem { addr, t: 1 }),
+            Operation::IntAck => None,
+        }
+    }
+
+    fn trace_cycle(&mut self) {
+        if self.trace.is_none() {
+            return;
+        }
+        let rfsh = match self.current_ops {
+            Some(Operation::Fetch | Operation::Nmi) => matches!(self.current_ops_ts, 2 | 3),
+            Some(Operation::IntAck) => self.current_ops_ts == 5,
+            _ => false,
+        };
+        let addr = if rfsh { self.ir() } else { self.signals.addr };
+        let mreq = rfsh || !matches!(self.signals.mem, SignalReq::None);
+        let iorq = !matches!(self.signals.port, SignalReq::None);
+        let rd = matches!(self.signals.mem, SignalReq::Read)
+            || matches!(self.signals.port, SignalReq::Read);
+        let wr = matches!(self.signals.mem, SignalReq::Write)
+            || matches!(self.signals.port, SignalReq::Write);
+        if let Some(trace) = self.trace.as_mut() {
+            trace.push(BusCycle {
+                t: trace.len(),
+                addr,
+                data: self.signals.data,
+                mreq,
+                iorq,
+                rd,
+                wr,
+                m1: self.regs.m1,
+                rfsh,
+            });
+        }
+    }
+
     fn decode_and_run(&mut self) {
         let mut fetch_done = false;
         match (self.fetched.prefix, self.fetched.op_code, self.fetched.n) {
@@ -191,8 +317,10 @@ impl CPU {
 
             (0xdd | 0xfd, 0xcb, None) => {
                 self.scheduler.push(Operation::MrPcD);
-                self.scheduler.push(Operation::Delay(2));
                 self.scheduler.push(Operation::MrPcN);
+                // on the opcode, after the displacement
+                let pc = self.regs.pc.wrapping_add(1);
+                self.scheduler.push(Operation::Delay(2, pc));
             }
 
             (0xdd | 0xfd, 0xdd, None) => {
@@ -207,6 +335,13 @@ impl CPU {
                 self.regs.index_mode = IndexMode::Iy;
             }
 
+            // the index prefix is dropped, ED instructions never use IX/IY
+            (0xdd | 0xfd, 0xed, None) => {
+                self.fetched.prefix = 0;
+                self.scheduler.push(Operation::Fetch);
+                self.regs.index_mode = IndexMode::Hl;
+            }
+
             (0xdd | 0xfd, 0xcb, Some(n)) => {
                 self.fetched.prefix = (self.fetched.prefix << 8) | 0xcb;
                 self.fetched.op_code = n;
@@ -239,7 +374,7 @@ impl CPU {
             (0 | 0xdd | 0xfd, 2) => alu(self, x, y, z),
             (0 | 0xdd | 0xfd, 3) => self.x3_ops(z, y, q, p),
             (0xed, _) => self.ed_ops(x, y, z, q, p),
-            _ => todo!("decode_and_run x:{}", x),
+            _ => unreachable!("Invalid prefix {:04x}", self.fetched.prefix),
         }
         self.fetched.decode_step += 1;
     }
@@ -265,6 +400,8 @@ impl CPU {
             }
             (1, 6, _, _) => self.regs.im = IM[y as usize],
             (1, 7, 0 | 1 | 2 | 3, _) => {
+                // IR as it was before the load
+                self.scheduler.push(Operation::Delay(1, self.ir()));
                 match y {
                     0 => self.regs.i = self.regs.a,
                     1 => self.regs.r = self.regs.a,
@@ -280,13 +417,13 @@ impl CPU {
                 }
 
                 self.fetched.done = true;
-                self.scheduler.push(Operation::Delay(1));
             }
             (1, 7, 4, _) => rdd(self),
             (1, 7, 5, _) => rld(self),
             (1, 7, 6 | 7, _) => {}
-            (2, _, _, _) => bli(self, y, z),
-            _ => todo!("ed_ops x:{} y:{} z:{} q:{} p:{}", x, y, z, q, p),
+            (2, 0..=3, 4..=7, _) => bli(self, y, z),
+            // the rest of the ED page does nothing, in the 8 T-states of the two fetches
+            _ => {}
         }
     }
 
@@ -305,20 +442,22 @@ impl CPU {
         match (z, self.fetched.n, self.regs.index_mode) {
             (6, None, IndexMode::Hl) => {
                 self.scheduler.push(Operation::MrAddrN(self.regs.get_rr(2)));
-                self.scheduler.push(Operation::Delay(1));
+                self.scheduler
+                    .push(Operation::Delay(1, self.regs.get_rr(2)));
             }
             (_, None, IndexMode::Ix | IndexMode::Iy) => {
-                self.scheduler.push(Operation::MrAddrN(
-                    self.regs.get_idx(self.fetched.d.unwrap()),
-                ));
-                self.scheduler.push(Operation::Delay(1));
+                self.regs.memptr = self.regs.get_idx(self.fetched.d.unwrap());
+                self.scheduler.push(Operation::MrAddrN(self.regs.memptr));
+                self.scheduler.push(Operation::Delay(1, self.regs.memptr));
             }
             (_, Some(n), _) => v = Some(n),
             (_, None, _) => v = Some(self.regs.get_r(z)),
         }
 
+        let from_memory = z == 6 || !matches!(self.regs.index_mode, IndexMode::Hl);
         let mut r = None;
         match (x, v) {
+            (1, Some(v)) if from_memory => bit_mem(self, y, v),
             (1, Some(v)) => _ = bit(self, y, v),
             (2, Some(v)) => r = Some(res(y, v)),
             (3, Some(v)) => r = Some(set(y, v)),
@@ -355,10 +494,9 @@ impl CPU {
                 self.scheduler.push(Operation::MrAddrN(self.regs.get_rr(2)))
             }
             (_, None, IndexMode::Ix | IndexMode::Iy) => {
-                self.scheduler.push(Operation::MrAddrN(
-                    self.regs.get_idx(self.fetched.d.unwrap()),
-                ));
-                self.scheduler.push(Operation::Delay(1));
+                self.regs.memptr = self.regs.get_idx(self.fetched.d.unwrap());
+                self.scheduler.push(Operation::MrAddrN(self.regs.memptr));
+                self.scheduler.push(Operation::Delay(1, self.regs.memptr));
             }
             (_, Some(n), _) => v = Some(n),
             (_, None, _) => v = Some(self.regs.get_r(z)),
@@ -380,7 +518,8 @@ impl CPU {
         match (z, res, self.regs.index_mode) {
             (6, Some(r), IndexMode::Hl) => {
                 self.fetched.done = true;
-                self.scheduler.push(Operation::Delay(1));
+                self.scheduler
+                    .push(Operation::Delay(1, self.regs.get_rr(2)));
                 self.scheduler.push(Operation::Mw8(self.regs.get_rr(2), r))
             }
             (_, Some(r), IndexMode::Ix | IndexMode::Iy) => {
@@ -433,6 +572,7 @@ impl CPU {
             7 => {
                 self.regs.iff1 = true;
                 self.regs.iff2 = true;
+                self.ei_shadow = true;
             }
             _ => unreachable!("Invalid x3_z3 instruction y={}", y),
         }
@@ -455,7 +595,7 @@ impl CPU {
             (1, 2) => self.regs.pc = self.regs.get_rr(2),
             (1, 3) => {
                 self.fetched.done = true;
-                self.scheduler.push(Operation::Delay(2));
+                self.scheduler.push(Operation::Delay(2, self.ir()));
                 self.regs.sp = self.regs.get_rr(2)
             }
             _ => unreachable!("Invalid x3_z1 instruction ({}, {})", q, p),
@@ -486,7 +626,7 @@ impl CPU {
             5 => dec_r(self, y),
             6 => ld_r_n(self, y),
             7 => self.x0_z7_ops(y),
-            _ => todo!("x0_ops z:{}", z),
+            _ => unreachable!("Invalid x0 instruction z={}", z),
         }
     }
 
@@ -509,14 +649,19 @@ impl CPU {
             0 => (), // NOP
             1 => self.regs.exafaf(),
             2..=7 => match self.fetched.n {
-                None => self.scheduler.push(Operation::MrPcN),
+                None => {
+                    // DJNZ decrements B in a 5 T-states opcode fetch
+                    if y == 2 {
+                        self.scheduler.push(Operation::Delay(1, self.ir()));
+                    }
+                    self.scheduler.push(Operation::MrPcN);
+                }
                 Some(_) => {
                     let mut jump = true;
                     match y {
                         2 => {
                             self.regs.b = self.regs.b.wrapping_sub(1);
                             jump = self.regs.b != 0;
-                            self.scheduler.push(Operation::Delay(1));
                         }
                         3 => {}
                         4 => jump = self.regs.f.z == false,
@@ -526,14 +671,17 @@ impl CPU {
                         _ => panic!(),
                     }
                     if jump {
+                        // on the displacement
+                        let d = self.regs.pc.wrapping_sub(1);
                         let jump = self.fetched.n.unwrap() as i8;
                         self.regs.pc = self.regs.pc.wrapping_add(jump as u16);
-                        self.scheduler.push(Operation::Delay(5));
+                        self.regs.memptr = self.regs.pc;
+                        self.scheduler.push(Operation::Delay(5, d));
                     }
                     self.fetched.done = true;
                 }
             },
-            _ => todo!("x0_z0_ops y:{}", y),
+            _ => unreachable!("Invalid x0_z0 instruction y={}", y),
         }
     }
 
@@ -550,7 +698,7 @@ impl CPU {
             1 => {
                 add_hl_rr(self, p);
                 self.fetched.done = true;
-                self.scheduler.push(Operation::Delay(7));
+                self.scheduler.push(Operation::Delay(7, self.ir()));
             }
             _ => panic!(),
         }
@@ -560,9 +708,10 @@ impl CPU {
         match q {
             0 => match p {
                 0 | 1 => {
+                    let addr = self.regs.get_rr(p);
+                    self.regs.memptr = ((self.regs.a as u16) << 8) | (addr.wrapping_add(1) & 0xff);
                     self.fetched.done = true;
-                    self.scheduler
-                        .push(Operation::Mw8(self.regs.get_rr(p), self.regs.a));
+                    self.scheduler.push(Operation::Mw8(addr, self.regs.a));
                 }
                 2 => match self.fetched.nn {
                     None => {
@@ -570,6 +719,7 @@ impl CPU {
                         self.scheduler.push(Operation::MrPcN);
                     }
                     Some(nn) => {
+                        self.regs.memptr = nn.wrapping_add(1);
                         self.fetched.done = true;
                         self.scheduler
                             .push(Operation::Mw16(nn, self.regs.get_rr(p)));
@@ -581,6 +731,8 @@ impl CPU {
                         self.scheduler.push(Operation::MrPcN);
                     }
                     Some(nn) => {
+                        self.regs.memptr =
+                            ((self.regs.a as u16) << 8) | (nn.wrapping_add(1) & 0xff);
                         self.fetched.done = true;
                         self.scheduler.push(Operation::Mw8(nn, self.regs.a));
                     }
@@ -592,7 +744,10 @@ impl CPU {
                     None => {
                         self.scheduler.push(Operation::MrAddrN(self.regs.get_rr(p)));
                     }
-                    Some(n) => self.regs.a = n,
+                    Some(n) => {
+                        self.regs.a = n;
+                        self.regs.memptr = self.regs.get_rr(p).wrapping_add(1);
+                    }
                 },
                 2 => match self.fetched.nn {
                     None => {
@@ -600,9 +755,11 @@ impl CPU {
                         self.scheduler.push(Operation::MrPcN);
                     }
                     Some(nn) => {
+                        self.regs.memptr = nn.wrapping_add(1);
                         self.fetched.done = true;
                         self.scheduler.push(Operation::MrAddrR(nn, 5));
-                        self.scheduler.push(Operation::MrAddrR(nn + 1, 4));
+                        self.scheduler
+                            .push(Operation::MrAddrR(nn.wrapping_add(1), 4));
                     }
                 },
                 3 => match self.fetched.nn {
@@ -611,6 +768,7 @@ impl CPU {
                         self.scheduler.push(Operation::MrPcN);
                     }
                     Some(nn) => {
+                        self.regs.memptr = nn.wrapping_add(1);
                         self.fetched.done = true;
                         self.scheduler.push(Operation::MrAddrR(nn, 7));
                     }
@@ -628,7 +786,7 @@ impl CPU {
             _ => panic!(),
         }
         self.fetched.done = true;
-        self.scheduler.push(Operation::Delay(2));
+        self.scheduler.push(Operation::Delay(2, self.ir()));
     }
 
     fn fetch(self: &mut Self) -> bool {
@@ -638,7 +796,10 @@ impl CPU {
                 self.regs.m1 = true;
                 self.signals.addr = self.regs.pc;
                 self.signals.mem = SignalReq::Read;
-                self.regs.pc = self.regs.pc.wrapping_add(1);
+                // halted, the byte after the HALT is read again and again as a NOP
+                if !self.halt {
+                    self.regs.pc = self.regs.pc.wrapping_add(1);
+                }
                 self.regs.r = (self.regs.r & 0x80) | ((self.regs.r.wrapping_add(1)) & 0x7f);
             }
             2 => {
@@ -646,7 +807,7 @@ impl CPU {
                 self.signals.mem = SignalReq::None;
                 self.fetched.prefix = self.fetched.prefix << 8;
                 self.fetched.prefix |= self.fetched.op_code as u16;
-                self.fetched.op_code = self.signals.data;
+                self.fetched.op_code = if self.halt { 0 } else { self.signals.data };
             }
             3 => {}
             4 => {
@@ -685,6 +846,10 @@ impl CPU {
             2 => self.signals.mem = SignalReq::Read,
             3 => {
                 self.regs.set_r(r, self.signals.data);
+                if r == 9 {
+                    // IM 2 jumps once the high byte of the handler is in PC
+                    self.regs.memptr = self.regs.pc;
+                }
                 self.signals.mem = SignalReq::None;
                 return true;
             }
@@ -693,6 +858,7 @@ impl CPU {
         false
     }
 
+    /// IO cycles are 4 T-states long, with the wait state every Z80 inserts after T2.
     fn pr_r(self: &mut Self, addr: u16, r: Option<u8>, flags: bool) -> bool {
         self.current_ops_ts += 1;
         match self.current_ops_ts {
@@ -711,10 +877,10 @@ impl CPU {
                     self.regs.f.p = PARITY_TABLE[self.signals.data as usize];
                     self.regs.f.z = self.signals.data == 0;
                     self.regs.f.s = self.signals.data & 0x0080 != 0;
+                    self.regs.f.set_xy(self.signals.data);
                 }
-
-                return true;
             }
+            4 => return true,
             _ => panic!(),
         }
         false
@@ -783,10 +949,8 @@ impl CPU {
                 self.signals.data = data;
             }
             2 => self.signals.port = SignalReq::Write,
-            3 => {
-                self.signals.port = SignalReq::None;
-                return true;
-            }
+            3 => self.signals.port = SignalReq::None,
+            4 => return true,
             _ => panic!(),
         }
         false
@@ -817,6 +981,12 @@ impl CPU {
         false
     }
 
+    /// What the CPU puts on the address bus while refreshing, and keeps there through most
+    /// internal T-states.
+    pub(crate) fn ir(&self) -> u16 {
+        ((self.regs.i as u16) << 8) | self.regs.r as u16
+    }
+
     fn delay(self: &mut Self, delay: u8) -> bool {
         self.current_ops_ts += 1;
         self.current_ops_ts == delay
@@ -829,28 +999,231 @@ impl CPU {
         )
     }
 
-    fn int01(&mut self) -> bool {
-        self.regs.iff1 = false;
-        self.regs.sp = self.regs.sp.wrapping_sub(2);
-        self.scheduler.push(Operation::Delay(1));
-        self.scheduler
-            .push(Operation::Mw16(self.regs.sp, self.regs.pc));
-        self.regs.pc = 0x0038;
-        return true;
+    /// Opcode fetch whose byte is ignored, then PC is pushed and the CPU jumps to 0x0066,
+    /// 11 T-states. IFF2 keeps the interrupt state, RETN restores it.
+    fn nmi(&mut self) -> bool {
+        self.current_ops_ts += 1;
+        match self.current_ops_ts {
+            1 => {
+                self.regs.m1 = true;
+                self.regs.iff1 = false;
+                self.signals.addr = self.regs.pc;
+                self.signals.mem = SignalReq::Read;
+                self.regs.r = (self.regs.r & 0x80) | ((self.regs.r.wrapping_add(1)) & 0x7f);
+            }
+            2 => {
+                self.regs.m1 = false;
+                self.signals.mem = SignalReq::None;
+            }
+            3 | 4 => {}
+            5 => {
+                self.fetched.done = true;
+                push_16(self, self.regs.pc);
+                self.regs.pc = 0x0066;
+                self.regs.memptr = self.regs.pc;
+                return true;
+            }
+            _ => panic!(),
+        }
+        false
+    }
+
+    /// M1 cycle with two wait states that reads the byte the host puts on the data bus,
+    /// PC is not incremented. 13 T-states in IM 1, 19 in IM 2; IM 0 runs the byte as the
+    /// opcode of an instruction, any operands are read from memory at PC.
+    fn int_ack(&mut self) -> bool {
+        self.current_ops_ts += 1;
+        match self.current_ops_ts {
+            1 => {
+                self.regs.m1 = true;
+                self.regs.iff1 = false;
+                self.regs.iff2 = false;
+                self.signals.addr = self.regs.pc;
+                self.regs.r = (self.regs.r & 0x80) | ((self.regs.r.wrapping_add(1)) & 0x7f);
+            }
+            2 | 3 => {}
+            4 => self.signals.port = SignalReq::Ack,
+            5 => {
+                self.regs.m1 = false;
+                self.signals.port = SignalReq::None;
+            }
+            6 => {
+                let data = self.signals.data;
+                match self.regs.im {
+                    0 => self.fetched.op_code = data,
+                    _ => {
+                        self.fetched.done = true;
+                        self.scheduler.push(Operation::Delay(1, self.ir()));
+                        push_16(self, self.regs.pc);
+                        if self.regs.im == 2 {
+                            let vector = ((self.regs.i as u16) << 8) | data as u16;
+                            self.scheduler.push(Operation::MrAddrR(vector, 8)); // C
+                            self.scheduler
+                                .push(Operation::MrAddrR(vector.wrapping_add(1), 9));
+                        // P
+                        } else {
+                            self.regs.pc = 0x0038;
+                            self.regs.memptr = self.regs.pc;
+                        }
+                    }
+                }
+                return true;
+            }
+            _ => panic!(),
+        }
+        false
+    }
+}
+
+/// Mid-instruction state is saved too: the fetched opcode, the pending operations and
+/// the T-state of the current one.
+impl SaveState for CPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.regs.save_state(w);
+        self.signals.save_state(w);
+        self.fetched.save_state(w);
+        w.u16(self.scheduler.len() as u16);
+        for op in &self.scheduler {
+            op.save_state(w);
+        }
+        w.bool(self.current_ops.is_some());
+        if let Some(op) = self.current_ops {
+            op.save_state(w);
+        }
+        w.u8(self.current_ops_ts);
+        w.bool(self.wait);
+        w.bool(self.halt);
+        w.bool(self.do_reset);
+        w.bool(self.nmi_pending);
+        w.bool(self.ei_shadow);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.regs.load_state(r)?;
+        self.signals.load_state(r)?;
+        self.fetched.load_state(r)?;
+        let len = r.u16()?;
+        self.scheduler.clear();
+        for _ in 0..len {
+            self.scheduler.push(Operation::read_state(r)?);
+        }
+        self.current_ops = if r.bool()? {
+            Some(Operation::read_state(r)?)
+        } else {
+            None
+        };
+        self.current_ops_ts = r.u8()?;
+        self.wait = r.bool()?;
+        self.halt = r.bool()?;
+        self.do_reset = r.bool()?;
+        self.nmi_pending = r.bool()?;
+        self.ei_shadow = r.bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Fetched {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.pc);
+        w.u8(self.op_code);
+        w.u16(self.prefix);
+        w.opt_u8(self.n);
+        w.opt_u16(self.nn);
+        w.opt_u8(self.d);
+        w.u8(self.decode_step);
+        w.bool(self.done);
     }
 
-    fn int02(&mut self) -> bool {
-        self.regs.iff1 = false;
-        self.regs.sp = self.regs.sp.wrapping_sub(2);
-        self.scheduler.push(Operation::Delay(1));
-        self.scheduler
-            .push(Operation::Mw16(self.regs.sp, self.regs.pc));
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.pc = r.u16()?;
+        self.op_code = r.u8()?;
+        self.prefix = r.u16()?;
+        self.n = r.opt_u8()?;
+        self.nn = r.opt_u16()?;
+        self.d = r.opt_u8()?;
+        self.decode_step = r.u8()?;
+        self.done = r.bool()?;
+        Ok(())
+    }
+}
 
-        let pos = (self.regs.i as u16) << 8;
+impl Operation {
+    fn save_state(&self, w: &mut StateWriter) {
+        match *self {
+            Operation::Fetch => w.u8(0),
+            Operation::MrPcN => w.u8(1),
+            Operation::Mw8(addr, data) => {
+                w.u8(2);
+                w.u16(addr);
+                w.u8(data);
+            }
+            Operation::Mw16(addr, data) => {
+                w.u8(3);
+                w.u16(addr);
+                w.u16(data);
+            }
+            Operation::MrAddrN(addr) => {
+                w.u8(4);
+                w.u16(addr);
+            }
+            Operation::MrAddrR(addr, r) => {
+                w.u8(5);
+                w.u16(addr);
+                w.u8(r);
+            }
+            Operation::Delay(delay, addr) => {
+                w.u8(6);
+                w.u8(delay);
+                w.u16(addr);
+            }
+            Operation::Pw8(addr, data) => {
+                w.u8(7);
+                w.u16(addr);
+                w.u8(data);
+            }
+            Operation::PrR(addr, r, flags) => {
+                w.u8(8);
+                w.u16(addr);
+                w.opt_u8(r);
+                w.bool(flags);
+            }
+            Operation::MrPcD => w.u8(9),
+            Operation::IntAck => w.u8(10),
+            Operation::Nmi => w.u8(11),
+        }
+    }
+
+    fn read_state(r: &mut StateReader) -> Result<Self, Error> {
+        Ok(match r.u8()? {
+            0 => Operation::Fetch,
+            1 => Operation::MrPcN,
+            2 => Operation::Mw8(r.u16()?, r.u8()?),
+            3 => Operation::Mw16(r.u16()?, r.u16()?),
+            4 => Operation::MrAddrN(r.u16()?),
+            5 => Operation::MrAddrR(r.u16()?, r.u8()?),
+            6 => Operation::Delay(r.u8()?, r.u16()?),
+            7 => Operation::Pw8(r.u16()?, r.u8()?),
+            8 => Operation::PrR(r.u16()?, r.opt_u8()?, r.bool()?),
+            9 => Operation::MrPcD,
+            10 => Operation::IntAck,
+            11 => Operation::Nmi,
+            _ => return Err(invalid_state("invalid CPU operation")),
+        })
+    }
+}
 
-        self.scheduler.push(Operation::MrAddrR(pos, 9)); // P
-        self.scheduler.push(Operation::MrAddrR(pos + 1, 8)); // C
-        true
+/// Instructions that write the flags, for the Q latch. POP AF and EX AF,AF' do not count.
+fn sets_flags(prefix: u16, op_code: u8) -> bool {
+    let (x, y, z, _, q) = decode(op_code);
+    match (prefix, x) {
+        (0xcb | 0xddcb | 0xfdcb, x) => x < 2,
+        (0xed, 1) => matches!((z, y), (0 | 2 | 4, _) | (7, 2..=5)),
+        (0xed, 2) => y >= 4 && z <= 3,
+        (0xed, _) => false,
+        (_, 0) => matches!((z, q), (1, 1) | (4 | 5 | 7, _)),
+        (_, 1) => false,
+        (_, 2) => true,
+        (_, _) => z == 6,
     }
 }
 
@@ -1,3 +1,4 @@
+pub mod bus;
 pub mod cpu;
 mod diss;
 mod ops_codes;
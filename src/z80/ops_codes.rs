@@ -38,9 +38,9 @@ pub fn ld_rr_mm(cpu: &mut CPU, r: u8) {
     }
 }
 
+/// PC is left past the HALT, the CPU runs NOPs until an interrupt is accepted.
 pub fn halt(cpu: &mut CPU) {
     cpu.halt = true;
-    cpu.regs.pc -= 1
 }
 
 pub fn ld_r_n(cpu: &mut CPU, y: u8) {
@@ -48,8 +48,10 @@ pub fn ld_r_n(cpu: &mut CPU, y: u8) {
         (6, IndexMode::Ix | IndexMode::Iy, None, None) => cpu.scheduler.push(Operation::MrPcD),
         (6, IndexMode::Ix | IndexMode::Iy, Some(_), None) => cpu.scheduler.push(Operation::MrPcN),
         (6, IndexMode::Ix | IndexMode::Iy, Some(d), Some(n)) => {
+            cpu.regs.memptr = cpu.regs.get_idx(d);
             cpu.fetched.done = true;
-            cpu.scheduler.push(Operation::Delay(2));
+            cpu.scheduler
+                .push(Operation::Delay(2, cpu.regs.pc.wrapping_sub(1)));
             cpu.scheduler.push(Operation::Mw8(cpu.regs.get_idx(d), n));
         }
         (_, _, _, None) => cpu.scheduler.push(Operation::MrPcN),
@@ -64,7 +66,7 @@ pub fn ld_r_n(cpu: &mut CPU, y: u8) {
 pub fn ld_r_r(cpu: &mut CPU, y: u8, z: u8) {
     match cpu.regs.index_mode {
         IndexMode::Hl => match (y, z, cpu.fetched.decode_step) {
-            (6, 6, _) => todo!(),
+            (6, 6, _) => halt(cpu),
 
             (_, 6, 0) => cpu.scheduler.push(Operation::MrAddrN(cpu.regs.get_rr(2))),
             (_, 6, 1) => {
@@ -86,27 +88,31 @@ pub fn ld_r_r(cpu: &mut CPU, y: u8, z: u8) {
             (6, _, 0) | (_, 6, 0) => cpu.scheduler.push(Operation::MrPcD),
 
             // LD r[z], (ix+d)
-            (_, 6, 1) => cpu
-                .scheduler
-                .push(Operation::MrAddrN(cpu.regs.get_idx(cpu.fetched.d.unwrap()))),
+            (_, 6, 1) => {
+                cpu.regs.memptr = cpu.regs.get_idx(cpu.fetched.d.unwrap());
+                cpu.scheduler
+                    .push(Operation::Delay(5, cpu.regs.pc.wrapping_sub(1)));
+                cpu.scheduler.push(Operation::MrAddrN(cpu.regs.memptr));
+            }
             (_, 6, 2) => {
                 if y == 4 || y == 5 {
                     cpu.regs.index_mode = IndexMode::Hl;
                 }
                 cpu.regs.set_r(y, cpu.fetched.n.unwrap());
-                cpu.scheduler.push(Operation::Delay(5));
                 cpu.fetched.done = true;
             }
 
             // LD (ix+d), r[z]
             (6, _, 1) => {
                 let get_idx = cpu.regs.get_idx(cpu.fetched.d.unwrap());
+                cpu.regs.memptr = get_idx;
                 if z == 4 || z == 5 {
                     cpu.regs.index_mode = IndexMode::Hl;
                 }
+                cpu.scheduler
+                    .push(Operation::Delay(5, cpu.regs.pc.wrapping_sub(1)));
                 cpu.scheduler
                     .push(Operation::Mw8(get_idx, cpu.regs.get_r(z)));
-                cpu.scheduler.push(Operation::Delay(5));
                 cpu.fetched.done = true;
             }
             (6, _, 2) => cpu.fetched.done = true,
@@ -149,13 +155,18 @@ fn inc_dec_r(cpu: &mut CPU, r: u8, is_inc: bool) {
                     v = dec(cpu, v);
                 }
                 cpu.fetched.done = true;
-                cpu.scheduler.push(Operation::Delay(1));
+                cpu.scheduler.push(Operation::Delay(1, cpu.regs.get_rr(2)));
                 cpu.scheduler.push(Operation::Mw8(cpu.regs.get_rr(2), v));
             }
         },
         (6, _) => match (cpu.fetched.n, cpu.fetched.d) {
             (None, None) => cpu.scheduler.push(Operation::MrPcD),
-            (None, Some(d)) => cpu.scheduler.push(Operation::MrAddrN(cpu.regs.get_idx(d))),
+            (None, Some(d)) => {
+                cpu.regs.memptr = cpu.regs.get_idx(d);
+                cpu.scheduler
+                    .push(Operation::Delay(5, cpu.regs.pc.wrapping_sub(1)));
+                cpu.scheduler.push(Operation::MrAddrN(cpu.regs.memptr));
+            }
             (Some(n), Some(d)) => {
                 let mut v = n;
                 if is_inc {
@@ -165,7 +176,7 @@ fn inc_dec_r(cpu: &mut CPU, r: u8, is_inc: bool) {
                 }
 
                 cpu.fetched.done = true;
-                cpu.scheduler.push(Operation::Delay(6));
+                cpu.scheduler.push(Operation::Delay(1, cpu.regs.get_idx(d)));
                 cpu.scheduler.push(Operation::Mw8(cpu.regs.get_idx(d), v));
             }
             _ => unreachable!("Invalid inc_r instruction"),
@@ -189,6 +200,7 @@ pub fn inc(cpu: &mut CPU, v: u8) -> u8 {
     cpu.regs.f.h = r & 0x0f == 0;
     cpu.regs.f.p = r == 0x80;
     cpu.regs.f.n = false;
+    cpu.regs.f.set_xy(r);
     r
 }
 
@@ -199,6 +211,7 @@ pub fn dec(cpu: &mut CPU, v: u8) -> u8 {
     cpu.regs.f.z = r == 0;
     cpu.regs.f.p = r == 0x7f;
     cpu.regs.f.n = true;
+    cpu.regs.f.set_xy(r);
     r
 }
 
@@ -207,6 +220,7 @@ pub fn rlca(cpu: &mut CPU) {
     cpu.regs.f.c = cpu.regs.a & 0x01 != 0;
     cpu.regs.f.h = false;
     cpu.regs.f.n = false;
+    cpu.regs.f.set_xy(cpu.regs.a);
 }
 
 pub fn rla(cpu: &mut CPU) {
@@ -218,6 +232,7 @@ pub fn rla(cpu: &mut CPU) {
     }
     cpu.regs.f.h = false;
     cpu.regs.f.n = false;
+    cpu.regs.f.set_xy(cpu.regs.a);
 }
 
 pub fn rrca(cpu: &mut CPU) {
@@ -225,6 +240,7 @@ pub fn rrca(cpu: &mut CPU) {
     cpu.regs.f.h = false;
     cpu.regs.f.n = false;
     cpu.regs.a = (cpu.regs.a >> 1) | (cpu.regs.a << 7);
+    cpu.regs.f.set_xy(cpu.regs.a);
 }
 
 pub fn rra(cpu: &mut CPU) {
@@ -236,6 +252,7 @@ pub fn rra(cpu: &mut CPU) {
     }
     cpu.regs.f.h = false;
     cpu.regs.f.n = false;
+    cpu.regs.f.set_xy(cpu.regs.a);
 }
 
 pub fn add_hl_rr(cpu: &mut CPU, p: u8) {
@@ -248,10 +265,12 @@ pub fn add_hl_rr(cpu: &mut CPU, p: u8) {
     let lookup =
         (((hl & 0x0800) >> 11) | ((v & 0x0800) >> 10) | (((result as u16) & 0x0800) >> 9)) as u8;
     cpu.regs.set_rr(2, result as u16);
+    cpu.regs.memptr = hl.wrapping_add(1);
 
     cpu.regs.f.n = false;
     cpu.regs.f.h = HALFCARRY_ADD_TABLE[lookup as usize];
     cpu.regs.f.c = (result & 0x10000) != 0;
+    cpu.regs.f.set_xy((result >> 8) as u8);
 }
 
 pub fn daa(cpu: &mut CPU) {
@@ -281,20 +300,30 @@ pub fn cpl(cpu: &mut CPU) {
     cpu.regs.a = !cpu.regs.a;
     cpu.regs.f.h = true;
     cpu.regs.f.n = true;
+    cpu.regs.f.set_xy(cpu.regs.a);
 }
 
 pub fn scf(cpu: &mut CPU) {
+    scf_ccf_xy(cpu);
     cpu.regs.f.h = false;
     cpu.regs.f.n = false;
     cpu.regs.f.c = true;
 }
 
 pub fn ccf(cpu: &mut CPU) {
+    scf_ccf_xy(cpu);
     cpu.regs.f.h = cpu.regs.f.c;
     cpu.regs.f.n = false;
     cpu.regs.f.c = !cpu.regs.f.c;
 }
 
+/// Flags 3 and 5 are ORed from A and F, but only when the previous instruction did not
+/// set the flags.
+fn scf_ccf_xy(cpu: &mut CPU) {
+    let f = cpu.regs.f.get();
+    cpu.regs.f.set_xy((cpu.regs.q ^ f) | cpu.regs.a);
+}
+
 pub fn alu(cpu: &mut CPU, x: u8, y: u8, z: u8) {
     let mut v: Option<u8> = None;
     match (x, z, cpu.regs.index_mode, cpu.fetched.d, cpu.fetched.n) {
@@ -302,8 +331,10 @@ pub fn alu(cpu: &mut CPU, x: u8, y: u8, z: u8) {
             cpu.scheduler.push(Operation::MrPcD);
         }
         (2, 6, IndexMode::Iy | IndexMode::Ix, Some(d), None) => {
-            cpu.scheduler.push(Operation::MrAddrN(cpu.regs.get_idx(d)));
-            cpu.scheduler.push(Operation::Delay(5));
+            cpu.regs.memptr = cpu.regs.get_idx(d);
+            cpu.scheduler
+                .push(Operation::Delay(5, cpu.regs.pc.wrapping_sub(1)));
+            cpu.scheduler.push(Operation::MrAddrN(cpu.regs.memptr));
         }
         (2, 6, IndexMode::Iy | IndexMode::Ix, Some(_), Some(n)) => v = Some(n),
 
@@ -335,6 +366,8 @@ fn cp(cpu: &mut CPU, v: u8) {
     let result = a.wrapping_sub(v as u16);
     update_flags_ula(cpu, v, result as u16, true);
     cpu.regs.a = a as u8;
+    // compared to the operand, not the result
+    cpu.regs.f.set_xy(v);
 }
 
 pub fn sub_a(cpu: &mut CPU, v: u8) {
@@ -387,6 +420,7 @@ fn update_flags_ula(cpu: &mut CPU, v: u8, result: u16, is_subtraction: bool) {
     cpu.regs.f.p = overflow_table[(lookup >> 4) as usize];
     cpu.regs.f.n = is_subtraction;
     cpu.regs.f.c = (result & 0x100) != 0;
+    cpu.regs.f.set_xy(cpu.regs.a);
 }
 
 fn xor(cpu: &mut CPU, s: u8) {
@@ -413,11 +447,12 @@ fn update_flags_ula_logic(cpu: &mut CPU) {
     cpu.regs.f.p = PARITY_TABLE[cpu.regs.a as usize];
     cpu.regs.f.n = false;
     cpu.regs.f.c = false;
+    cpu.regs.f.set_xy(cpu.regs.a);
 }
 
 pub fn ret_cc(cpu: &mut CPU, y: u8) {
     match cpu.fetched.decode_step {
-        0 => cpu.scheduler.push(Operation::Delay(1)),
+        0 => cpu.scheduler.push(Operation::Delay(1, cpu.ir())),
         1 | 2 => {
             if cpu.if_cc(y) {
                 ret(cpu)
@@ -436,6 +471,7 @@ pub fn ret(cpu: &mut CPU) {
         Some(nn) => {
             cpu.regs.sp = cpu.regs.sp.wrapping_add(2);
             cpu.regs.pc = nn;
+            cpu.regs.memptr = nn;
         }
     }
 }
@@ -447,6 +483,7 @@ pub fn jp(cpu: &mut CPU, y: Option<u8>) {
             cpu.scheduler.push(Operation::MrPcN);
         }
         Some(nn) => {
+            cpu.regs.memptr = nn;
             let mut jump = true;
             match y {
                 Some(y) => jump = cpu.if_cc(y),
@@ -466,16 +503,16 @@ pub fn call(cpu: &mut CPU, y: Option<u8>) {
             cpu.scheduler.push(Operation::MrPcN);
         }
         Some(nn) => {
+            cpu.regs.memptr = nn;
             let mut jump = true;
             match y {
                 Some(y) => jump = cpu.if_cc(y),
                 None => (),
             }
             if jump {
-                cpu.regs.sp = cpu.regs.sp.wrapping_sub(2);
-                cpu.scheduler.push(Operation::Delay(1));
                 cpu.scheduler
-                    .push(Operation::Mw16(cpu.regs.sp, cpu.regs.pc));
+                    .push(Operation::Delay(1, cpu.regs.pc.wrapping_sub(1)));
+                push_16(cpu, cpu.regs.pc);
                 cpu.regs.pc = nn;
                 cpu.fetched.done = true;
             }
@@ -485,10 +522,18 @@ pub fn call(cpu: &mut CPU, y: Option<u8>) {
 
 pub fn push(cpu: &mut CPU, r: u8) {
     cpu.fetched.done = true;
+    cpu.scheduler.push(Operation::Delay(1, cpu.ir()));
+    push_16(cpu, cpu.regs.get_rr2(r));
+}
+
+/// Schedules the writes of a push: the high byte goes first, to SP-1, then the low byte.
+pub fn push_16(cpu: &mut CPU, data: u16) {
     cpu.regs.sp = cpu.regs.sp.wrapping_sub(2);
-    cpu.scheduler.push(Operation::Delay(1));
-    cpu.scheduler
-        .push(Operation::Mw16(cpu.regs.sp, cpu.regs.get_rr2(r)));
+    cpu.scheduler.push(Operation::Mw8(
+        cpu.regs.sp.wrapping_add(1),
+        (data >> 8) as u8,
+    ));
+    cpu.scheduler.push(Operation::Mw8(cpu.regs.sp, data as u8));
 }
 
 pub fn pop(cpu: &mut CPU, r: u8) {
@@ -506,11 +551,10 @@ pub fn pop(cpu: &mut CPU, r: u8) {
 
 pub fn rst(cpu: &mut CPU, y: u8) {
     cpu.fetched.done = true;
-    cpu.regs.sp = cpu.regs.sp.wrapping_sub(2);
-    cpu.scheduler.push(Operation::Delay(1));
-    cpu.scheduler
-        .push(Operation::Mw16(cpu.regs.sp, cpu.regs.pc));
+    cpu.scheduler.push(Operation::Delay(1, cpu.ir()));
+    push_16(cpu, cpu.regs.pc);
     cpu.regs.pc = (y * 8) as u16;
+    cpu.regs.memptr = cpu.regs.pc;
 }
 
 fn set_flags_rot(cpu: &mut CPU, res: u8) {
@@ -519,6 +563,7 @@ fn set_flags_rot(cpu: &mut CPU, res: u8) {
     cpu.regs.f.h = false;
     cpu.regs.f.s = res & 0x80 != 0;
     cpu.regs.f.p = PARITY_TABLE[res as usize];
+    cpu.regs.f.set_xy(res);
 }
 
 pub fn rlc(cpu: &mut CPU, _z: u8, v: u8) -> u8 {
@@ -578,6 +623,7 @@ pub fn srl(cpu: &mut CPU, _z: u8, v: u8) -> u8 {
 }
 
 pub fn bit(cpu: &mut CPU, bit: u8, v: u8) -> u8 {
+    cpu.regs.f.set_xy(v);
     let v = v & 1 << bit;
     cpu.regs.f.n = false;
     cpu.regs.f.h = true;
@@ -587,6 +633,12 @@ pub fn bit(cpu: &mut CPU, bit: u8, v: u8) -> u8 {
     v
 }
 
+/// BIT n,(HL) and BIT n,(IX+d) take flags 3 and 5 from the high byte of MEMPTR.
+pub fn bit_mem(cpu: &mut CPU, bit_n: u8, v: u8) {
+    bit(cpu, bit_n, v);
+    cpu.regs.f.set_xy((cpu.regs.memptr >> 8) as u8);
+}
+
 pub fn res(y: u8, v: u8) -> u8 {
     let bit = y as u8;
     let b = 1 << bit;
@@ -603,8 +655,9 @@ pub fn out_na(cpu: &mut CPU) {
     match cpu.fetched.decode_step {
         0 => cpu.scheduler.push(Operation::MrPcN),
         1 => {
-            let port = (cpu.fetched.n.unwrap() as u16) | (cpu.regs.a as u16) << 8;
-            cpu.scheduler.push(Operation::Delay(1));
+            let n = cpu.fetched.n.unwrap();
+            let port = (n as u16) | (cpu.regs.a as u16) << 8;
+            cpu.regs.memptr = ((cpu.regs.a as u16) << 8) | (n.wrapping_add(1) as u16);
             cpu.scheduler.push(Operation::Pw8(port, cpu.regs.a));
         }
         2 => {}
@@ -617,7 +670,7 @@ pub fn in_na(cpu: &mut CPU) {
         None => cpu.scheduler.push(Operation::MrPcN),
         Some(n) => {
             let port = (cpu.regs.a as u16) << 8 | n as u16;
-            cpu.scheduler.push(Operation::Delay(1));
+            cpu.regs.memptr = port.wrapping_add(1);
             cpu.scheduler.push(Operation::PrR(port, Some(7), false));
             cpu.fetched.done = true;
         }
@@ -633,37 +686,42 @@ pub fn ex_sp_hl(cpu: &mut CPU) {
         Some(nn) => {
             let hl = cpu.regs.get_rr(2);
             cpu.regs.set_rr(2, nn);
+            cpu.regs.memptr = nn;
             cpu.fetched.done = true;
-            cpu.scheduler.push(Operation::Delay(3));
-            cpu.scheduler.push(Operation::Mw16(cpu.regs.sp, hl));
+            cpu.scheduler
+                .push(Operation::Delay(1, cpu.regs.sp.wrapping_add(1)));
+            cpu.scheduler
+                .push(Operation::Mw8(cpu.regs.sp.wrapping_add(1), (hl >> 8) as u8));
+            cpu.scheduler.push(Operation::Mw8(cpu.regs.sp, hl as u8));
+            cpu.scheduler.push(Operation::Delay(2, cpu.regs.sp));
         }
     }
 }
 
 pub fn in_c(cpu: &mut CPU) {
+    cpu.regs.memptr = cpu.regs.get_rr(0).wrapping_add(1);
     cpu.scheduler
         .push(Operation::PrR(cpu.regs.get_rr(0), None, true));
-    cpu.scheduler.push(Operation::Delay(1));
     cpu.fetched.done = true;
 }
 
 pub fn in_r_c(cpu: &mut CPU, r: u8) {
+    cpu.regs.memptr = cpu.regs.get_rr(0).wrapping_add(1);
     cpu.scheduler
         .push(Operation::PrR(cpu.regs.get_rr(0), Some(r), true));
-    cpu.scheduler.push(Operation::Delay(1));
     cpu.fetched.done = true;
 }
 
 pub fn out_c(cpu: &mut CPU) {
+    cpu.regs.memptr = cpu.regs.get_rr(0).wrapping_add(1);
     cpu.scheduler.push(Operation::Pw8(cpu.regs.get_rr(0), 0));
-    cpu.scheduler.push(Operation::Delay(1));
     cpu.fetched.done = true;
 }
 
 pub fn out_c_r(cpu: &mut CPU, r: u8) {
+    cpu.regs.memptr = cpu.regs.get_rr(0).wrapping_add(1);
     cpu.scheduler
         .push(Operation::Pw8(cpu.regs.get_rr(0), cpu.regs.get_r(r)));
-    cpu.scheduler.push(Operation::Delay(1));
     cpu.fetched.done = true;
 }
 
@@ -683,9 +741,11 @@ pub fn sbc_hl(cpu: &mut CPU, ss: u16) {
     cpu.regs.f.p = OVERFLOW_SUB_TABLE[(lookup >> 4) as usize];
     cpu.regs.f.h = (hl & 0xFFF) < (ss & 0xFFF) + (if cpu.regs.f.c { 1 } else { 0 }); // Set half-carry flag based on lower 12 bits
     cpu.regs.f.c = carry1 || carry2; // Set carry flag based on subtraction overflow
+    cpu.regs.f.set_xy((result_with_carry >> 8) as u8);
+    cpu.regs.memptr = hl.wrapping_add(1);
 
     cpu.fetched.done = true;
-    cpu.scheduler.push(Operation::Delay(7));
+    cpu.scheduler.push(Operation::Delay(7, cpu.ir()));
 }
 
 pub fn adc_hl(cpu: &mut CPU, ss: u16) {
@@ -704,9 +764,11 @@ pub fn adc_hl(cpu: &mut CPU, ss: u16) {
     cpu.regs.f.c = carry1 || carry2;
     cpu.regs.f.p = OVERFLOW_ADD_TABLE[(lookup >> 4) as usize];
     cpu.regs.f.h = (hl & 0xFFF) + (ss & 0xFFF) > 0xFFF; // Set half-carry flag based on lower 12 bits
+    cpu.regs.f.set_xy((result_with_carry >> 8) as u8);
+    cpu.regs.memptr = hl.wrapping_add(1);
 
     cpu.fetched.done = true;
-    cpu.scheduler.push(Operation::Delay(7));
+    cpu.scheduler.push(Operation::Delay(7, cpu.ir()));
 }
 
 pub fn ld_nn_rr(cpu: &mut CPU, p: u8) {
@@ -716,6 +778,7 @@ pub fn ld_nn_rr(cpu: &mut CPU, p: u8) {
             cpu.scheduler.push(Operation::MrPcN);
         }
         Some(nn) => {
+            cpu.regs.memptr = nn.wrapping_add(1);
             cpu.fetched.done = true;
             cpu.scheduler.push(Operation::Mw16(nn, cpu.regs.get_rr(p)));
         }
@@ -729,6 +792,7 @@ pub fn ld_rr_nn(cpu: &mut CPU, p: u8) {
             cpu.scheduler.push(Operation::MrPcN);
         }
         1 => {
+            cpu.regs.memptr = cpu.fetched.nn.unwrap().wrapping_add(1);
             cpu.scheduler
                 .push(Operation::MrAddrN(cpu.fetched.nn.unwrap()));
             cpu.scheduler
@@ -754,11 +818,12 @@ pub fn rdd(cpu: &mut CPU) {
             let new_a = (cpu.regs.a & 0xf0) | nl;
             let new_n = (al << 4) | nh;
 
-            cpu.scheduler.push(Operation::Delay(4));
+            cpu.scheduler.push(Operation::Delay(4, cpu.regs.get_rr(2)));
             cpu.scheduler
                 .push(Operation::Mw8(cpu.regs.get_rr(2), new_n));
             cpu.fetched.done = true;
             cpu.regs.a = new_a;
+            cpu.regs.memptr = cpu.regs.get_rr(2).wrapping_add(1);
             update_flags_after_rdd_rld(cpu);
         }
     }
@@ -775,11 +840,12 @@ pub fn rld(cpu: &mut CPU) {
             let new_a = (cpu.regs.a & 0xf0) | nh;
             let new_n = (nl << 4) | al;
 
-            cpu.scheduler.push(Operation::Delay(4));
+            cpu.scheduler.push(Operation::Delay(4, cpu.regs.get_rr(2)));
             cpu.scheduler
                 .push(Operation::Mw8(cpu.regs.get_rr(2), new_n));
             cpu.fetched.done = true;
             cpu.regs.a = new_a;
+            cpu.regs.memptr = cpu.regs.get_rr(2).wrapping_add(1);
             update_flags_after_rdd_rld(cpu);
         }
     }
@@ -792,6 +858,7 @@ fn update_flags_after_rdd_rld(cpu: &mut CPU) {
     cpu.regs.f.p = PARITY_TABLE[a as usize];
     cpu.regs.f.h = false;
     cpu.regs.f.n = false;
+    cpu.regs.f.set_xy(a);
 }
 
 pub fn ld_a_ir_flags(cpu: &mut CPU) {
@@ -801,6 +868,7 @@ pub fn ld_a_ir_flags(cpu: &mut CPU) {
     cpu.regs.f.p = cpu.regs.iff2;
     cpu.regs.f.h = false;
     cpu.regs.f.n = false;
+    cpu.regs.f.set_xy(a);
 }
 
 pub fn bli(cpu: &mut CPU, a: u8, b: u8) {
@@ -833,7 +901,7 @@ fn otir_otdr(cpu: &mut CPU, sub: bool) {
         2 => {
             outi_outd(cpu, sub);
             if !cpu.regs.f.z {
-                cpu.scheduler.push(Operation::Delay(5))
+                cpu.scheduler.push(Operation::Delay(5, cpu.regs.bc()))
             }
         }
         3 => {
@@ -851,7 +919,8 @@ fn inir_indr(cpu: &mut CPU, sub: bool) {
         2 => {
             ini_ind(cpu, sub);
             if !cpu.regs.f.z {
-                cpu.scheduler.push(Operation::Delay(5))
+                cpu.scheduler
+                    .push(Operation::Delay(5, unstep(cpu.regs.hl(), sub)))
             }
         }
         3 => {
@@ -869,11 +938,13 @@ fn cpir_cpdr(cpu: &mut CPU, sub: bool) {
         2 => {
             cpi_cpd(cpu, sub);
             if cpu.regs.f.p && !cpu.regs.f.z {
-                cpu.scheduler.push(Operation::Delay(5))
+                cpu.scheduler
+                    .push(Operation::Delay(5, unstep(cpu.regs.hl(), sub)))
             }
         }
         3 => {
             cpu.regs.pc = cpu.regs.pc.wrapping_sub(2);
+            cpu.regs.memptr = cpu.regs.pc.wrapping_add(1);
         }
         _ => unreachable!("Invalid cpir_cpdr instruction"),
     }
@@ -887,27 +958,37 @@ fn ldir_lddr(cpu: &mut CPU, sub: bool) {
         2 => {
             ldi_ldd(cpu, sub);
             if cpu.regs.f.p {
-                cpu.scheduler.push(Operation::Delay(5))
+                cpu.scheduler
+                    .push(Operation::Delay(5, unstep(cpu.regs.de(), sub)))
             }
         }
         3 => {
             cpu.regs.pc = cpu.regs.pc.wrapping_sub(2);
+            cpu.regs.memptr = cpu.regs.pc.wrapping_add(1);
         }
         _ => unreachable!("Invalid ldir instruction"),
     }
 }
 
+/// The address a block instruction worked on, before it stepped it.
+fn unstep(addr: u16, sub: bool) -> u16 {
+    if sub {
+        addr.wrapping_add(1)
+    } else {
+        addr.wrapping_sub(1)
+    }
+}
+
 fn outi_outd(cpu: &mut CPU, sub: bool) {
     match cpu.fetched.decode_step {
         0 => {
-            cpu.scheduler.push(Operation::Delay(1));
+            cpu.scheduler.push(Operation::Delay(1, cpu.ir()));
             cpu.scheduler.push(Operation::MrAddrN(cpu.regs.get_rr(2)))
         }
         1 => {
             cpu.regs.set_r(0, cpu.regs.get_r(0).wrapping_sub(1));
             cpu.scheduler
                 .push(Operation::Pw8(cpu.regs.get_rr(0), cpu.fetched.n.unwrap()));
-            cpu.scheduler.push(Operation::Delay(1));
         }
         2 => {
             if sub {
@@ -920,6 +1001,12 @@ fn outi_outd(cpu: &mut CPU, sub: bool) {
             let value = cpu.fetched.n.unwrap();
             let aux = cpu.regs.get_r(5).wrapping_add(value);
             let p = (aux & 0x07) ^ b;
+            cpu.regs.memptr = if sub {
+                cpu.regs.get_rr(0).wrapping_sub(1)
+            } else {
+                cpu.regs.get_rr(0).wrapping_add(1)
+            };
+            cpu.regs.f.set_xy(b);
 
             cpu.regs.f.z = b == 0;
             cpu.regs.f.s = b & 0x80 != 0;
@@ -938,16 +1025,20 @@ fn outi_outd(cpu: &mut CPU, sub: bool) {
 fn ini_ind(cpu: &mut CPU, sub: bool) {
     match cpu.fetched.decode_step {
         0 => {
-            cpu.scheduler.push(Operation::Delay(1));
+            cpu.scheduler.push(Operation::Delay(1, cpu.ir()));
             cpu.scheduler
                 .push(Operation::PrR(cpu.regs.get_rr(0), None, true))
         }
         1 => {
             cpu.scheduler
                 .push(Operation::Mw8(cpu.regs.get_rr(2), cpu.fetched.n.unwrap()));
-            cpu.scheduler.push(Operation::Delay(1));
         }
         2 => {
+            cpu.regs.memptr = if sub {
+                cpu.regs.get_rr(0).wrapping_sub(1)
+            } else {
+                cpu.regs.get_rr(0).wrapping_add(1)
+            };
             cpu.regs.set_r(0, cpu.regs.get_r(0).wrapping_sub(1));
             if sub {
                 cpu.regs.set_rr(2, cpu.regs.get_rr(2).wrapping_sub(1));
@@ -968,6 +1059,8 @@ fn ini_ind(cpu: &mut CPU, sub: bool) {
             cpu.regs.f.c = aux < c;
             cpu.regs.f.n = value & 0x80 != 0;
             cpu.regs.f.z = cpu.regs.get_r(0) == 0;
+            cpu.regs.f.s = b & 0x80 != 0;
+            cpu.regs.f.set_xy(b);
 
             let p = (aux & 0x07) ^ b;
             cpu.regs.f.p = PARITY_TABLE[p as usize];
@@ -980,7 +1073,7 @@ fn cpi_cpd(cpu: &mut CPU, sub: bool) {
     match cpu.fetched.decode_step {
         0 => cpu.scheduler.push(Operation::MrAddrN(cpu.regs.get_rr(2))),
         1 => {
-            cpu.scheduler.push(Operation::Delay(5));
+            cpu.scheduler.push(Operation::Delay(5, cpu.regs.get_rr(2)));
         }
         2 => {
             let data = cpu.fetched.n.unwrap();
@@ -999,6 +1092,16 @@ fn cpi_cpd(cpu: &mut CPU, sub: bool) {
             cpu.regs.f.z = result == 0;
             cpu.regs.f.p = cpu.regs.get_rr(0) != 0;
             cpu.regs.f.n = true;
+
+            // flag 5 is bit 1 of A - (HL) - H
+            let n = result.wrapping_sub(cpu.regs.f.h as u8);
+            cpu.regs.f.f3 = n & 0x08 != 0;
+            cpu.regs.f.f5 = n & 0x02 != 0;
+            cpu.regs.memptr = if sub {
+                cpu.regs.memptr.wrapping_sub(1)
+            } else {
+                cpu.regs.memptr.wrapping_add(1)
+            };
         }
         _ => unreachable!("Invalid cpi instruction"),
     }
@@ -1008,9 +1111,9 @@ fn ldi_ldd(cpu: &mut CPU, sub: bool) {
     match cpu.fetched.decode_step {
         0 => cpu.scheduler.push(Operation::MrAddrN(cpu.regs.get_rr(2))),
         1 => {
-            cpu.scheduler.push(Operation::Delay(2));
             cpu.scheduler
                 .push(Operation::Mw8(cpu.regs.get_rr(1), cpu.fetched.n.unwrap()));
+            cpu.scheduler.push(Operation::Delay(2, cpu.regs.get_rr(1)));
         }
         2 => {
             if sub {
@@ -1026,7 +1129,10 @@ fn ldi_ldd(cpu: &mut CPU, sub: bool) {
             cpu.regs.f.h = false;
             cpu.regs.f.n = false;
 
-            // }
+            // flag 5 is bit 1 of A + (HL)
+            let n = cpu.regs.a.wrapping_add(cpu.fetched.n.unwrap());
+            cpu.regs.f.f3 = n & 0x08 != 0;
+            cpu.regs.f.f5 = n & 0x02 != 0;
         }
         _ => unreachable!("Invalid ldi instruction"),
     }
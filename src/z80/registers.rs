@@ -1,10 +1,14 @@
+use std::io::Error;
+
+use crate::state::{invalid_state, SaveState, StateReader, StateWriter};
+
 macro_rules! make_reg_functions {
     ($name:ident, $name2:ident, $l:ident, $h:ident) => {
-        pub fn $name(self: &Self) -> u16 {
+        pub fn $name(&self) -> u16 {
             ((self.$l as u16) << 8) | (self.$h as u16)
         }
 
-        fn $name2(self: &mut Self, v: u16) {
+        pub fn $name2(&mut self, v: u16) {
             self.$l = ((v >> 8) as u8);
             self.$h = (v as u8);
         }
@@ -62,6 +66,12 @@ pub struct Registers {
     pub iff2: bool,
 
     pub im: u8,
+
+    /// Internal WZ register, visible through the undocumented flags.
+    pub memptr: u16,
+    /// Flags set by the last instruction, 0 if it left them alone. SCF and CCF take the
+    /// undocumented flags from it.
+    pub q: u8,
 }
 
 impl Registers {
@@ -96,6 +106,8 @@ impl Registers {
             iff1: false,
             iff2: false,
             im: 0,
+            memptr: 0,
+            q: 0,
         }
     }
 
@@ -258,7 +270,7 @@ impl Registers {
     pub fn dump_registers(&self) -> String {
         format!(
             "{:04x} {:04x} {:04x} {:04x} {:04x} {:04x} {:04x} {:04x} {:04x} {:04x} {:04x} {:04x}",
-            self.af() & 0xffd7,
+            self.af(),
             self.bc(),
             self.de(),
             self.hl(),
@@ -329,6 +341,12 @@ impl Flags {
         res
     }
 
+    /// Undocumented flags 3 and 5, copied from bits 3 and 5 of `v`.
+    pub fn set_xy(&mut self, v: u8) {
+        self.f3 = v & 0b00001000 != 0;
+        self.f5 = v & 0b00100000 != 0;
+    }
+
     pub fn set(&mut self, b: u8) {
         self.c = b & 0b00000001 != 0;
         self.n = b & 0b00000010 != 0;
@@ -346,3 +364,60 @@ impl Default for Flags {
         Self::new()
     }
 }
+
+impl SaveState for Registers {
+    fn save_state(&self, w: &mut StateWriter) {
+        for v in [
+            self.af(),
+            self.bc(),
+            self.de(),
+            self.hl(),
+            self.af_aux(),
+            self.bc_aux(),
+            self.de_aux(),
+            self.hl_aux(),
+            self.ix(),
+            self.iy(),
+            self.sp,
+            self.pc,
+            self.memptr,
+        ] {
+            w.u16(v);
+        }
+        w.u8(self.q);
+        w.bool(self.m1);
+        w.u8(self.r);
+        w.u8(self.i);
+        w.u8(match self.index_mode {
+            IndexMode::Hl => 0,
+            IndexMode::Ix => 1,
+            IndexMode::Iy => 2,
+        });
+        w.bool(self.iff1);
+        w.bool(self.iff2);
+        w.u8(self.im);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        let mut regs = [0; 12];
+        for v in regs.iter_mut() {
+            *v = r.u16()?;
+        }
+        self.set_all_regs(regs);
+        self.memptr = r.u16()?;
+        self.q = r.u8()?;
+        self.m1 = r.bool()?;
+        self.r = r.u8()?;
+        self.i = r.u8()?;
+        self.index_mode = match r.u8()? {
+            0 => IndexMode::Hl,
+            1 => IndexMode::Ix,
+            2 => IndexMode::Iy,
+            _ => return Err(invalid_state("invalid index mode")),
+        };
+        self.iff1 = r.bool()?;
+        self.iff2 = r.bool()?;
+        self.im = r.u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+use std::io::Error;
+
+use crate::state::{invalid_state, SaveState, StateReader, StateWriter};
+
+const REG_MASK: [u8; 16] = [
+    0xff, 0x0f, 0xff, 0x0f, 0xff, 0x0f, 0x1f, 0xff, 0x1f, 0x1f, 0x1f, 0xff, 0xff, 0x0f, 0xff, 0xff,
+];
+
+/// DAC output levels, measured on a real chip.
+const VOLUME: [f32; 16] = [
+    0.0, 0.0100, 0.0145, 0.0211, 0.0307, 0.0455, 0.0645, 0.1074, 0.1266, 0.2050, 0.2922, 0.3728,
+    0.4925, 0.6353, 0.8056, 1.0,
+];
+
+const R_MIXER: usize = 7;
+const R_ENV_SHAPE: usize = 13;
+
+/// AY-3-8912 programmable sound generator.
+///
+/// Three square wave channels, a shared noise generator and a shared envelope generator.
+/// `tick` is called once per CPU T-state; the chip runs at half the CPU clock and its
+/// generators advance every 8 chip cycles (16 T-states).
+pub struct AY {
+    regs: [u8; 16],
+    selected: usize,
+
+    prescaler: u8,
+
+    tone_counter: [u16; 3],
+    tone_out: [bool; 3],
+
+    noise_counter: u16,
+    noise_half: bool,
+    noise_rng: u32,
+    noise_out: bool,
+
+    env_counter: u32,
+    env_pos: u8,
+    env_attack: bool,
+    env_holding: bool,
+}
+
+impl Default for AY {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AY {
+    pub fn new() -> Self {
+        Self {
+            regs: [0; 16],
+            selected: 0,
+            prescaler: 0,
+            tone_counter: [0; 3],
+            tone_out: [false; 3],
+            noise_counter: 0,
+            noise_half: false,
+            noise_rng: 1,
+            noise_out: false,
+            env_counter: 0,
+            env_pos: 0,
+            env_attack: false,
+            env_holding: false,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Port 0xFFFD write
+    pub fn select(&mut self, reg: u8) {
+        self.selected = (reg & 0x0f) as usize;
+    }
+
+    pub fn selected(&self) -> u8 {
+        self.selected as u8
+    }
+
+    /// Port 0xFFFD read
+    pub fn read(&self) -> u8 {
+        self.regs[self.selected]
+    }
+
+    /// Port 0xBFFD write
+    pub fn write(&mut self, data: u8) {
+        self.set_reg(self.selected, data);
+    }
+
+    pub fn regs(&self) -> [u8; 16] {
+        self.regs
+    }
+
+    /// Loads all the registers, as saved in a snapshot.
+    pub fn restore(&mut self, regs: &[u8; 16], selected: u8) {
+        for (reg, data) in regs.iter().enumerate() {
+            self.set_reg(reg, *data);
+        }
+        self.select(selected);
+    }
+
+    pub fn set_reg(&mut self, reg: usize, data: u8) {
+        self.regs[reg] = data & REG_MASK[reg];
+        if reg == R_ENV_SHAPE {
+            self.reset_envelope();
+        }
+    }
+
+    pub fn tick(&mut self) {
+        self.prescaler += 1;
+        if self.prescaler < 16 {
+            return;
+        }
+        self.prescaler = 0;
+
+        for ch in 0..3 {
+            self.tone_counter[ch] += 1;
+            if self.tone_counter[ch] >= self.tone_period(ch) {
+                self.tone_counter[ch] = 0;
+                self.tone_out[ch] = !self.tone_out[ch];
+            }
+        }
+
+        // the noise generator runs at half the tone rate
+        self.noise_half = !self.noise_half;
+        if self.noise_half {
+            self.noise_counter += 1;
+            if self.noise_counter >= self.noise_period() {
+                self.noise_counter = 0;
+                let bit = (self.noise_rng ^ (self.noise_rng >> 3)) & 0x01;
+                self.noise_rng = (self.noise_rng >> 1) | (bit << 16);
+                self.noise_out = self.noise_rng & 0x01 != 0;
+            }
+        }
+
+        self.env_counter += 1;
+        if self.env_counter >= self.env_period() * 2 {
+            self.env_counter = 0;
+            self.step_envelope();
+        }
+    }
+
+    /// Mono mix of the three channels, from 0.0 to 1.0.
+    pub fn output(&self) -> f32 {
+        let mixer = self.regs[R_MIXER];
+        let mut out = 0.0;
+        for ch in 0..3 {
+            let tone_off = mixer & (0x01 << ch) != 0;
+            let noise_off = mixer & (0x08 << ch) != 0;
+            if (self.tone_out[ch] || tone_off) && (self.noise_out || noise_off) {
+                out += VOLUME[self.level(ch) as usize];
+            }
+        }
+        out / 3.0
+    }
+
+    fn level(&self, ch: usize) -> u8 {
+        let amplitude = self.regs[8 + ch];
+        if amplitude & 0x10 != 0 {
+            self.env_level()
+        } else {
+            amplitude & 0x0f
+        }
+    }
+
+    fn tone_period(&self, ch: usize) -> u16 {
+        let period = (self.regs[ch * 2] as u16) | ((self.regs[ch * 2 + 1] as u16) << 8);
+        period.max(1)
+    }
+
+    fn noise_period(&self) -> u16 {
+        (self.regs[6] as u16).max(1)
+    }
+
+    fn env_period(&self) -> u32 {
+        ((self.regs[11] as u32) | ((self.regs[12] as u32) << 8)).max(1)
+    }
+
+    fn env_level(&self) -> u8 {
+        if self.env_attack {
+            self.env_pos
+        } else {
+            15 - self.env_pos
+        }
+    }
+
+    fn reset_envelope(&mut self) {
+        self.env_counter = 0;
+        self.env_pos = 0;
+        self.env_attack = self.regs[R_ENV_SHAPE] & 0x04 != 0;
+        self.env_holding = false;
+    }
+
+    fn step_envelope(&mut self) {
+        if self.env_holding {
+            return;
+        }
+        self.env_pos += 1;
+        if self.env_pos < 16 {
+            return;
+        }
+
+        let shape = self.regs[R_ENV_SHAPE];
+        let cont = shape & 0x08 != 0;
+        let alt = shape & 0x02 != 0;
+        let hold = shape & 0x01 != 0;
+
+        if !cont {
+            // shapes 0-7 drop to 0 after the first cycle
+            self.env_holding = true;
+            self.env_attack = true;
+            self.env_pos = 0;
+        } else if hold {
+            self.env_holding = true;
+            if alt {
+                self.env_attack = !self.env_attack;
+            }
+            self.env_pos = 15;
+        } else {
+            if alt {
+                self.env_attack = !self.env_attack;
+            }
+            self.env_pos = 0;
+        }
+    }
+}
+
+impl SaveState for AY {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.raw(&self.regs);
+        w.u8(self.selected as u8);
+        w.u8(self.prescaler);
+        for ch in 0..3 {
+            w.u16(self.tone_counter[ch]);
+            w.bool(self.tone_out[ch]);
+        }
+        w.u16(self.noise_counter);
+        w.bool(self.noise_half);
+        w.u32(self.noise_rng);
+        w.bool(self.noise_out);
+        w.u32(self.env_counter);
+        w.u8(self.env_pos);
+        w.bool(self.env_attack);
+        w.bool(self.env_holding);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.regs.copy_from_slice(r.raw(16)?);
+        self.selected = (r.u8()? & 0x0f) as usize;
+        self.prescaler = r.u8()?;
+        for ch in 0..3 {
+            self.tone_counter[ch] = r.u16()?;
+            self.tone_out[ch] = r.bool()?;
+        }
+        self.noise_counter = r.u16()?;
+        self.noise_half = r.bool()?;
+        self.noise_rng = r.u32()?;
+        self.noise_out = r.bool()?;
+        self.env_counter = r.u32()?;
+        self.env_pos = r.u8()?;
+        self.env_attack = r.bool()?;
+        self.env_holding = r.bool()?;
+        // the counters never get past the longest periods
+        if self.prescaler >= 16 || self.env_pos > 15 {
+            return Err(invalid_state("bad AY prescaler or envelope step"));
+        }
+        if self.tone_counter.iter().any(|counter| *counter > 0x0fff)
+            || self.noise_counter > 0x1f
+            || self.env_counter >= 0x20000
+        {
+            return Err(invalid_state("bad AY counter"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,510 @@
+use std::io::Error;
+
+use crate::state::{invalid_state, SaveState, StateReader, StateWriter};
+
+use super::tap::{Block, DataBlock, GeneralizedBlock, Symbol, Tap, MS};
+
+/// What happens to the EAR level at the start of a pulse.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Edge {
+    Toggle,
+    Keep,
+    Low,
+    High,
+}
+
+/// Tape player: turns the blocks of a `Tap` into pulses and plays them, one T-state per `tick`.
+pub struct TapeDeck {
+    tap: Option<Tap>,
+    playing: bool,
+    level: bool,
+
+    block: usize,
+    pulses: Vec<(u32, Edge)>,
+    /// Block the pulses were made from, to make them again when a state is loaded.
+    pulses_block: usize,
+    pulse: usize,
+    remaining: u32,
+
+    /// (first block of the loop, repetitions left)
+    loops: Vec<(usize, u16)>,
+    /// (call block, next call of the sequence)
+    call: Option<(usize, usize)>,
+
+    /// Loader detection for fast load
+    pc: u16,
+    ear_read: bool,
+    loop_pc: u16,
+    loop_hits: u32,
+    idle: u32,
+
+    pub auto_start: bool,
+    pub fast_load: bool,
+    pub is_48k: bool,
+}
+
+/// Consecutive EAR reads from the same instruction that look like an edge waiting loop.
+const LOADER_HITS: u32 = 8;
+/// Instructions without reading the EAR bit before leaving fast load.
+const LOADER_IDLE: u32 = 2000;
+
+impl TapeDeck {
+    pub fn new(is_48k: bool) -> Self {
+        Self {
+            tap: None,
+            playing: false,
+            level: false,
+            block: 0,
+            pulses: Vec::new(),
+            pulses_block: 0,
+            pulse: 0,
+            remaining: 0,
+            loops: Vec::new(),
+            call: None,
+            pc: 0,
+            ear_read: false,
+            loop_pc: 0,
+            loop_hits: 0,
+            idle: 0,
+            auto_start: false,
+            fast_load: false,
+            is_48k,
+        }
+    }
+
+    pub fn insert(&mut self, tap: Tap) {
+        self.tap = Some(tap);
+        self.rewind();
+    }
+
+    pub fn eject(&mut self) {
+        self.tap = None;
+        self.rewind();
+    }
+
+    pub fn tap(&self) -> Option<&Tap> {
+        self.tap.as_ref()
+    }
+
+    pub fn tap_mut(&mut self) -> Option<&mut Tap> {
+        self.tap.as_mut()
+    }
+
+    pub fn play(&mut self) {
+        self.playing = self.tap.is_some();
+    }
+
+    pub fn stop(&mut self) {
+        self.playing = false;
+    }
+
+    pub fn rewind(&mut self) {
+        self.playing = false;
+        self.level = false;
+        self.block = 0;
+        self.pulses.clear();
+        self.pulses_block = 0;
+        self.pulse = 0;
+        self.remaining = 0;
+        self.loops.clear();
+        self.call = None;
+        self.loop_hits = 0;
+        if let Some(tap) = self.tap.as_mut() {
+            tap.rewind();
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn level(&self) -> bool {
+        self.level
+    }
+
+    /// Index of the block being played.
+    pub fn block(&self) -> usize {
+        self.block.saturating_sub(1)
+    }
+
+    /// Stops the tape and moves it to the start of `block`.
+    pub fn seek(&mut self, block: usize) {
+        self.rewind();
+        self.block = block;
+        if let Some(tap) = self.tap.as_mut() {
+            tap.seek(block);
+        }
+    }
+
+    /// Called on the ROM LD-BYTES routine, starts the tape if `auto_start` is set.
+    pub fn on_ld_bytes(&mut self) {
+        if self.auto_start && !self.playing {
+            self.play();
+        }
+    }
+
+    /// The CPU is reading the ULA port during the current instruction.
+    pub fn on_ear_read(&mut self) {
+        self.ear_read = true;
+    }
+
+    /// Called with the value returned by `CPU::tick` at every instruction boundary.
+    pub fn on_instruction(&mut self, pc: u16) {
+        if self.ear_read {
+            if self.pc == self.loop_pc {
+                self.loop_hits = self.loop_hits.saturating_add(1);
+            } else {
+                self.loop_pc = self.pc;
+                self.loop_hits = 1;
+            }
+            self.idle = 0;
+        } else if self.idle < LOADER_IDLE {
+            self.idle += 1;
+        } else {
+            self.loop_hits = 0;
+        }
+        self.ear_read = false;
+        self.pc = pc;
+    }
+
+    /// A loader is waiting for edges: the machine can run flat out until it stops polling.
+    pub fn is_fast_forwarding(&self) -> bool {
+        self.fast_load && self.playing && self.loop_hits >= LOADER_HITS
+    }
+
+    /// T-states until the next edge, 0 when the deck is stopped.
+    pub fn next_edge(&self) -> u32 {
+        if self.playing {
+            self.remaining
+        } else {
+            0
+        }
+    }
+
+    /// Advances the tape one T-state and returns the EAR level.
+    pub fn tick(&mut self) -> bool {
+        if !self.playing {
+            return self.level;
+        }
+
+        while self.remaining == 0 {
+            if self.pulse == self.pulses.len() {
+                // the block can also stop the tape
+                if !self.next_block() || !self.playing {
+                    self.playing = false;
+                    return self.level;
+                }
+                continue;
+            }
+            let (len, edge) = self.pulses[self.pulse];
+            self.pulse += 1;
+            self.level = match edge {
+                Edge::Toggle => !self.level,
+                Edge::Keep => self.level,
+                Edge::Low => false,
+                Edge::High => true,
+            };
+            self.remaining = len;
+        }
+
+        self.remaining -= 1;
+        self.level
+    }
+
+    /// Moves to the next block that makes pulses, following the flow control blocks.
+    fn next_block(&mut self) -> bool {
+        self.pulses.clear();
+        self.pulse = 0;
+
+        let Some(tap) = self.tap.as_ref() else {
+            return false;
+        };
+
+        // a jump to itself, or jumps going round, would never get to any pulses
+        let mut visited = 0;
+        while self.pulses.is_empty() {
+            let Some(block) = tap.blocks.get(self.block) else {
+                return false;
+            };
+            if visited == tap.blocks.len() {
+                return false;
+            }
+            visited += 1;
+            let current = self.block;
+            self.block += 1;
+
+            match block {
+                Block::Pause(0) => {
+                    self.playing = false;
+                    return true;
+                }
+                Block::Jump(offset) => self.block = jump(current, *offset),
+                Block::LoopStart(count) => self.loops.push((self.block, *count)),
+                Block::LoopEnd => {
+                    if let Some((start, count)) = self.loops.pop() {
+                        if count > 1 {
+                            self.loops.push((start, count - 1));
+                            self.block = start;
+                        }
+                    }
+                }
+                Block::Call(calls) => {
+                    if let Some(offset) = calls.first() {
+                        self.call = Some((current, 1));
+                        self.block = jump(current, *offset);
+                    }
+                }
+                Block::Return => {
+                    if let Some((call, next)) = self.call.take() {
+                        match &tap.blocks[call] {
+                            Block::Call(calls) if next < calls.len() => {
+                                self.call = Some((call, next + 1));
+                                self.block = jump(call, calls[next]);
+                            }
+                            _ => self.block = call + 1,
+                        }
+                    }
+                }
+                Block::StopIf48k => {
+                    if self.is_48k {
+                        self.playing = false;
+                        return true;
+                    }
+                }
+                Block::GroupStart(_)
+                | Block::GroupEnd
+                | Block::Text(_)
+                | Block::ArchiveInfo(_)
+                | Block::Info(_) => (),
+                block => {
+                    Self::block_pulses(&mut self.pulses, block, &tap.data);
+                    self.pulses_block = current;
+                }
+            }
+        }
+        true
+    }
+
+    /// Pulses of the blocks that make sound, nothing for the others.
+    fn block_pulses(pulses: &mut Vec<(u32, Edge)>, block: &Block, data: &[u8]) {
+        match block {
+            Block::Data(block) => Self::data_pulses(pulses, block, data),
+            Block::PureTone { pulse, count } => {
+                for _ in 0..*count {
+                    pulses.push((*pulse, Edge::Toggle));
+                }
+            }
+            Block::PulseSeq(seq) => {
+                for pulse in seq {
+                    pulses.push((*pulse, Edge::Toggle));
+                }
+            }
+            Block::DirectRecording {
+                ts_per_sample,
+                pause,
+                range,
+                last_byte_len,
+            } => {
+                let data = &data[range.clone()];
+                for (i, byte) in data.iter().enumerate() {
+                    // the file is checked on load, a tape can also come from a state
+                    let bits = if i == data.len() - 1 {
+                        (*last_byte_len as usize).clamp(1, 8)
+                    } else {
+                        8
+                    };
+                    for b in 0..bits {
+                        let edge = if byte & (0x80 >> b) != 0 {
+                            Edge::High
+                        } else {
+                            Edge::Low
+                        };
+                        pulses.push((*ts_per_sample, edge));
+                    }
+                }
+                Self::pause_pulses(pulses, *pause);
+            }
+            Block::Generalized(block) => Self::generalized_pulses(pulses, block, data),
+            Block::Pause(pause) => Self::pause_pulses(pulses, *pause),
+            Block::SetLevel(level) => {
+                let edge = if *level { Edge::High } else { Edge::Low };
+                pulses.push((0, edge));
+            }
+            _ => (),
+        }
+    }
+
+    fn data_pulses(pulses: &mut Vec<(u32, Edge)>, block: &DataBlock, data: &[u8]) {
+        for _ in 0..block.pilot_len {
+            pulses.push((block.pilot, Edge::Toggle));
+        }
+        if block.sync1 > 0 {
+            pulses.push((block.sync1, Edge::Toggle));
+        }
+        if block.sync2 > 0 {
+            pulses.push((block.sync2, Edge::Toggle));
+        }
+
+        let data = &data[block.range.clone()];
+        for (i, byte) in data.iter().enumerate() {
+            let bits = if i == data.len() - 1 {
+                (block.last_byte_len as usize).clamp(1, 8)
+            } else {
+                8
+            };
+            for b in 0..bits {
+                let len = if byte & (0x80 >> b) != 0 {
+                    block.one
+                } else {
+                    block.zero
+                };
+                pulses.push((len, Edge::Toggle));
+                pulses.push((len, Edge::Toggle));
+            }
+        }
+        Self::pause_pulses(pulses, block.pause);
+    }
+
+    fn generalized_pulses(pulses: &mut Vec<(u32, Edge)>, block: &GeneralizedBlock, data: &[u8]) {
+        for (symbol, repeat) in &block.pilot_stream {
+            if let Some(symbol) = block.pilot_symbols.get(*symbol as usize) {
+                for _ in 0..*repeat {
+                    Self::symbol_pulses(pulses, symbol);
+                }
+            }
+        }
+
+        let data = &data[block.range.clone()];
+        let bits = block.bits_per_symbol as usize;
+        for n in 0..block.data_len as usize {
+            let mut symbol = 0;
+            for b in (n * bits)..((n + 1) * bits) {
+                let byte = data.get(b / 8).copied().unwrap_or(0);
+                let bit = byte & (0x80 >> (b % 8)) != 0;
+                symbol = (symbol << 1) | bit as usize;
+            }
+            if let Some(symbol) = block.data_symbols.get(symbol) {
+                Self::symbol_pulses(pulses, symbol);
+            }
+        }
+        Self::pause_pulses(pulses, block.pause);
+    }
+
+    fn symbol_pulses(pulses: &mut Vec<(u32, Edge)>, symbol: &Symbol) {
+        for (i, pulse) in symbol.pulses.iter().enumerate() {
+            let edge = match (i, symbol.flags & 0x03) {
+                (0, 1) => Edge::Keep,
+                (0, 2) => Edge::Low,
+                (0, 3) => Edge::High,
+                _ => Edge::Toggle,
+            };
+            pulses.push((*pulse, edge));
+        }
+    }
+
+    /// The last edge of a block lasts 1ms, then the level stays low for the rest of the pause.
+    fn pause_pulses(pulses: &mut Vec<(u32, Edge)>, pause: u32) {
+        if pause == 0 {
+            return;
+        }
+        let edge = pause.min(MS);
+        pulses.push((edge, Edge::Toggle));
+        if pause > edge {
+            pulses.push((pause - edge, Edge::Low));
+        }
+    }
+}
+
+/// The tape is saved with the state, the deck settings are not.
+impl SaveState for TapeDeck {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.tap.is_some());
+        if let Some(tap) = self.tap.as_ref() {
+            w.str(&tap.name);
+            w.bytes(&tap.data);
+            w.u32(tap.position() as u32);
+        }
+        w.bool(self.playing);
+        w.bool(self.level);
+        w.u32(self.block as u32);
+        w.bool(!self.pulses.is_empty());
+        w.u32(self.pulses_block as u32);
+        w.u32(self.pulse as u32);
+        w.u32(self.remaining);
+        w.u16(self.loops.len() as u16);
+        for (start, count) in &self.loops {
+            w.u32(*start as u32);
+            w.u16(*count);
+        }
+        w.bool(self.call.is_some());
+        let (call, next) = self.call.unwrap_or_default();
+        w.u32(call as u32);
+        w.u32(next as u32);
+        w.u16(self.pc);
+        w.bool(self.ear_read);
+        w.u16(self.loop_pc);
+        w.u32(self.loop_hits);
+        w.u32(self.idle);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.tap = if r.bool()? {
+            let name = r.str()?;
+            let data = r.bytes()?.to_vec();
+            let mut tap = Tap::from_data(data, name)?;
+            tap.seek(r.u32()? as usize);
+            Some(tap)
+        } else {
+            None
+        };
+        self.playing = r.bool()?;
+        self.level = r.bool()?;
+        self.block = r.u32()? as usize;
+        let has_pulses = r.bool()?;
+        self.pulses_block = r.u32()? as usize;
+        self.pulse = r.u32()? as usize;
+        self.remaining = r.u32()?;
+
+        self.pulses.clear();
+        if has_pulses {
+            let tap = self
+                .tap
+                .as_ref()
+                .ok_or_else(|| invalid_state("tape pulses without a tape"))?;
+            let block = tap
+                .blocks
+                .get(self.pulses_block)
+                .ok_or_else(|| invalid_state("invalid tape block"))?;
+            Self::block_pulses(&mut self.pulses, block, &tap.data);
+        }
+        if self.pulse > self.pulses.len() {
+            return Err(invalid_state("invalid tape pulse"));
+        }
+
+        self.loops.clear();
+        for _ in 0..r.u16()? {
+            let start = r.u32()? as usize;
+            self.loops.push((start, r.u16()?));
+        }
+        let has_call = r.bool()?;
+        let call = (r.u32()? as usize, r.u32()? as usize);
+        let call_ok = self
+            .tap
+            .as_ref()
+            .is_some_and(|tap| call.0 < tap.blocks.len());
+        if has_call && !call_ok {
+            return Err(invalid_state("invalid tape call block"));
+        }
+        self.call = has_call.then_some(call);
+        self.pc = r.u16()?;
+        self.ear_read = r.bool()?;
+        self.loop_pc = r.u16()?;
+        self.loop_hits = r.u32()?;
+        self.idle = r.u32()?;
+        Ok(())
+    }
+}
+
+fn jump(block: usize, offset: i16) -> usize {
+    (block as isize + offset as isize).max(0) as usize
+}
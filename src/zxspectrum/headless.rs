@@ -0,0 +1,309 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use crate::z80::registers::Registers;
+
+use super::screenshot::{self, ShotOptions};
+use super::snapshot::{Snapshot, EXTENSIONS};
+use super::ula::{key, NoFrames, ULA};
+use super::zx128k::Zx128k;
+use super::zx48k::Zx48k;
+
+/// Frames a chord is held, and then released, while typing.
+const HOLD_FRAMES: u32 = 5;
+/// Frames run when `--frames` is not given.
+const DEFAULT_FRAMES: u32 = 500;
+
+pub const USAGE: &str = "\
+usage: b2t80s_rust --headless [options] [FILE]
+
+  FILE               .sna, .z80 or .szx snapshot to start from, or .tap/.tzx tape to insert
+  --128k             boot a 128K, 128K snapshots always do
+  --melodik          plug an AY interface into the 48K
+  --frames N         frames to run, 500 by default
+  --until-pc ADDR    stop when the CPU gets to ADDR, fails if it does not
+  --keys SCRIPT      chords to type, like \"@100 j sym+p sym+p enter\", @N waits N frames
+  --screenshot FILE  save the screen at the end, as .png, .scr or .ppm
+  --no-border        leave the border out of the screenshot
+  --scale N          screenshot scale, 1 or 2
+  --dump-mem FILE    save the 64K the CPU sees at the end
+
+Numbers are decimal, or hex with 0x or $. The registers are printed at the end.";
+
+/// What the runner drives, both models implement it.
+pub trait Machine {
+    /// Runs one T-state, returns the PC at instruction boundaries.
+    fn step(&mut self) -> Option<u16>;
+    fn ula(&mut self) -> &mut ULA;
+    fn regs(&self) -> Registers;
+    /// Reads memory as the CPU sees it now.
+    fn peek(&self, addr: u16) -> u8;
+    /// The screen memory the ULA shows, bitmap and attributes.
+    fn screen(&self) -> Vec<u8>;
+}
+
+/// Command line of the headless runner.
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+    pub is_128k: bool,
+    pub melodik: bool,
+    pub file: Option<PathBuf>,
+    pub frames: u32,
+    pub until_pc: Option<u16>,
+    pub keys: KeyScript,
+    pub screenshot: Option<PathBuf>,
+    pub shot: ShotOptions,
+    pub dump_mem: Option<PathBuf>,
+}
+
+impl Options {
+    /// Parses the arguments after the program name, `--headless` among them.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, Error> {
+        let mut options = Options {
+            frames: DEFAULT_FRAMES,
+            ..Default::default()
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| invalid(format!("{} needs a value", arg)))
+            };
+            match arg.as_str() {
+                "--headless" => (),
+                "--128k" => options.is_128k = true,
+                "--melodik" => options.melodik = true,
+                "--frames" => options.frames = number(&value()?)?,
+                "--until-pc" => {
+                    let addr = number(&value()?)?;
+                    options.until_pc = Some(
+                        u16::try_from(addr)
+                            .map_err(|_| invalid(format!("bad address {}", addr)))?,
+                    );
+                }
+                "--keys" => options.keys = KeyScript::parse(&value()?)?,
+                "--screenshot" => options.screenshot = Some(PathBuf::from(value()?)),
+                "--no-border" => options.shot.border = false,
+                "--scale" => {
+                    options.shot.scale = match number(&value()?)? {
+                        scale @ (1 | 2) => scale as usize,
+                        scale => return Err(invalid(format!("bad scale {}", scale))),
+                    }
+                }
+                "--dump-mem" => options.dump_mem = Some(PathBuf::from(value()?)),
+                _ if arg.starts_with("--") => {
+                    return Err(invalid(format!("unknown option {}", arg)))
+                }
+                _ if options.file.is_none() => options.file = Some(PathBuf::from(arg)),
+                _ => return Err(invalid(format!("unexpected argument {}", arg))),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Keys typed by the runner, one chord after the other.
+#[derive(Debug, Default, PartialEq)]
+pub struct KeyScript {
+    /// (frame, keyboard rows) of every change.
+    changes: Vec<(u32, [u8; 8])>,
+}
+
+impl KeyScript {
+    /// Words are chords of key names joined by `+`, `@N` waits N frames.
+    pub fn parse(script: &str) -> Result<Self, Error> {
+        let mut changes = Vec::new();
+        let mut frame = 0;
+        for word in script.split_whitespace() {
+            if let Some(frames) = word.strip_prefix('@') {
+                frame += number(frames)?;
+                continue;
+            }
+            let mut rows = [0; 8];
+            for name in word.split('+') {
+                let (row, bit) =
+                    key(name).ok_or_else(|| invalid(format!("unknown key {}", name)))?;
+                rows[row] |= 1 << bit;
+            }
+            changes.push((frame, rows));
+            changes.push((frame + HOLD_FRAMES, [0; 8]));
+            frame += 2 * HOLD_FRAMES;
+        }
+        Ok(Self { changes })
+    }
+
+    /// The keyboard rows from `frame` on, when they change then.
+    pub fn at(&self, frame: u32) -> Option<[u8; 8]> {
+        self.changes
+            .iter()
+            .find(|(at, _)| *at == frame)
+            .map(|(_, rows)| *rows)
+    }
+}
+
+/// How the run ended.
+#[derive(Debug)]
+pub struct Outcome {
+    /// Frames run, the last one may be partial.
+    pub frames: u32,
+    /// The CPU got to `--until-pc`.
+    pub reached: bool,
+    pub regs: Registers,
+}
+
+/// Entry point of `--headless`, returns the exit code: 1 on errors, 2 when `--until-pc` was
+/// not reached.
+pub fn main(args: impl IntoIterator<Item = String>) -> i32 {
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return 1;
+        }
+    };
+    match run(&options) {
+        Ok(outcome) => {
+            println!("frames: {}", outcome.frames);
+            println!("{}", dump_registers(&outcome.regs));
+            match options.until_pc {
+                Some(pc) if !outcome.reached => {
+                    eprintln!("PC {:04x} not reached", pc);
+                    2
+                }
+                _ => 0,
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
+
+/// Boots the machine, loads the file and runs the script.
+pub fn run(options: &Options) -> Result<Outcome, Error> {
+    let (snapshot, tape) = match options.file.as_deref() {
+        Some(path) if is_snapshot(path) => (Some(Snapshot::load(path)?), None),
+        Some(path) => (None, Some(path)),
+        None => (None, None),
+    };
+    let is_128k = options.is_128k || snapshot.as_ref().is_some_and(|s| s.is_128k());
+
+    // nobody sends key events or messages, or plays the sound
+    let (_, keys) = mpsc::channel();
+    let (machine_ctl_tx, machine_ctl_rx) = mpsc::channel();
+    let frames = Box::new(NoFrames);
+    if is_128k {
+        let mut zx = Zx128k::new(frames, keys, machine_ctl_rx, machine_ctl_tx, None);
+        zx.set_tape_prompt(false);
+        if let Some(snapshot) = snapshot.as_ref() {
+            zx.restore(snapshot);
+        }
+        if let Some(path) = tape {
+            zx.insert_tape(path)?;
+        }
+        drive(&mut zx, options)
+    } else {
+        let mut zx = Zx48k::new(frames, keys, machine_ctl_rx, machine_ctl_tx, None);
+        zx.set_tape_prompt(false);
+        zx.set_melodik(options.melodik);
+        if let Some(snapshot) = snapshot.as_ref() {
+            zx.restore(snapshot);
+        }
+        if let Some(path) = tape {
+            zx.insert_tape(path)?;
+        }
+        drive(&mut zx, options)
+    }
+}
+
+fn drive<M: Machine>(zx: &mut M, options: &Options) -> Result<Outcome, Error> {
+    zx.ula().set_turbo(true);
+    zx.ula().set_live_input(false);
+
+    let mut frames = 0;
+    let mut reached = false;
+    while frames < options.frames && !reached {
+        if let Some(rows) = options.keys.at(frames) {
+            zx.ula().set_keyboard(rows);
+        }
+        reached = run_frame(zx, options.until_pc);
+        frames += 1;
+    }
+
+    if let Some(path) = options.screenshot.as_deref() {
+        let screen = zx.screen();
+        screenshot::save(path, zx.ula().last_frame(), &screen, options.shot)?;
+    }
+    if let Some(path) = options.dump_mem.as_deref() {
+        let memory: Vec<u8> = (0..=0xffff).map(|addr| zx.peek(addr)).collect();
+        fs::write(path, memory)?;
+    }
+    Ok(Outcome {
+        frames,
+        reached,
+        regs: zx.regs(),
+    })
+}
+
+/// Runs to the next interrupt, when the screen holds a whole frame; returns if the CPU got
+/// to `until_pc` first.
+fn run_frame<M: Machine>(zx: &mut M, until_pc: Option<u16>) -> bool {
+    let mut last = zx.ula().ts_in_frame();
+    loop {
+        if let Some(pc) = zx.step() {
+            if Some(pc) == until_pc {
+                return true;
+            }
+        }
+        let ts = zx.ula().ts_in_frame();
+        if ts < last {
+            return false;
+        }
+        last = ts;
+    }
+}
+
+pub fn dump_registers(regs: &Registers) -> String {
+    format!(
+        "PC={:04x} SP={:04x} AF={:04x} BC={:04x} DE={:04x} HL={:04x} IX={:04x} IY={:04x} \
+         AF'={:04x} BC'={:04x} DE'={:04x} HL'={:04x} I={:02x} R={:02x} IM={} IFF1={}",
+        regs.pc,
+        regs.sp,
+        regs.af(),
+        regs.bc(),
+        regs.de(),
+        regs.hl(),
+        regs.ix(),
+        regs.iy(),
+        regs.af_aux(),
+        regs.bc_aux(),
+        regs.de_aux(),
+        regs.hl_aux(),
+        regs.i,
+        regs.r,
+        regs.im,
+        regs.iff1 as u8,
+    )
+}
+
+fn is_snapshot(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+/// Decimal, or hex with a 0x or $ prefix.
+fn number(s: &str) -> Result<u32, Error> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix('$')) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| invalid(format!("bad number {}", s)))
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
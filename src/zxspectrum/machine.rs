@@ -0,0 +1,566 @@
+use rfd::FileDialog;
+use tokio::task;
+use tokio::time::MissedTickBehavior;
+
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::signals::SignalReq;
+use crate::state::{SaveState, StateReader, StateWriter, MACHINE_48K, QUICK_SLOTS};
+use crate::z80::bus::Bus;
+use crate::z80::cpu::CPU;
+use crate::z80::registers::Registers;
+
+use super::deck::TapeDeck;
+use super::headless::Machine;
+use super::rewind::History;
+use super::screenshot::{self, ShotOptions, SCR_SIZE};
+use super::snapshot::{Hardware, Snapshot, TapeImage, EXTENSIONS};
+use super::tap::Tap;
+use super::ula::{FrameSink, KeyChange, Timings, ULA};
+
+#[derive(Debug)]
+pub enum MachineMessage {
+    CPUWait,
+    CPUResume,
+    CPUSetRegisters(Registers),
+    Reset,
+    /// Presses the NMI button.
+    Nmi,
+    TapLoad(PathBuf),
+    TapePlay,
+    TapeStop,
+    TapeRewind,
+    TapeFastLoad(bool),
+    SnapshotLoad(PathBuf),
+    SnapshotSave(PathBuf),
+    ScreenshotSave(PathBuf, ShotOptions),
+    QuickSave(usize),
+    QuickLoad(usize),
+    /// Goes back this many seconds.
+    Rewind(u32),
+    StepBack,
+}
+
+#[derive(Debug)]
+pub(crate) enum TapState {
+    Empty,
+    Loading,
+    Ready,
+}
+
+/// What sets the models apart: memory, paging and the devices on the bus. It saves what it
+/// holds in the machine state, between the ULA and the tape deck.
+pub trait Model: SaveState + Sized {
+    /// Machine of the save states.
+    const MACHINE: u8;
+    const HARDWARE: Hardware;
+    const TIMINGS: Timings;
+    const NAME: &'static str;
+
+    fn new() -> Self;
+
+    /// The bus the CPU runs on.
+    fn bus<'a>(&'a mut self, ula: &'a mut ULA) -> impl Bus + 'a;
+
+    /// Reads memory as the CPU sees it now.
+    fn read(&self, addr: u16) -> u8;
+
+    /// Writes memory as the CPU would, the ROM is left alone.
+    fn write(&mut self, addr: u16, data: u8);
+
+    /// What the ULA fetches from the screen at `addr`.
+    fn screen_read(&self, addr: u16) -> u8;
+
+    /// The screen memory the ULA shows, from the bitmap on.
+    fn screen(&self) -> &[u8];
+
+    /// Runs the other devices for a T-state of the CPU.
+    fn tick(&mut self, ula: &mut ULA);
+
+    /// The 48K BASIC ROM is paged in, the tape traps only apply to it.
+    fn basic_rom(&self) -> bool;
+
+    fn reset(&mut self);
+
+    /// Fills in the memory and devices of a snapshot taken by the machine.
+    fn snapshot(&self, snapshot: &mut Snapshot);
+
+    /// Restores the memory and devices of a snapshot.
+    fn restore(&mut self, snapshot: &Snapshot);
+}
+
+/// A Spectrum: the CPU, the ULA and the tape deck around the memory and devices of a model.
+pub struct Spectrum<M: Model> {
+    model: M,
+
+    cpu: CPU,
+    pub(super) ula: ULA,
+
+    deck: TapeDeck,
+    tap_state: TapState,
+    /// Ask for a tape file when the ROM loads with the deck empty.
+    tape_prompt: bool,
+
+    slots: [Option<Vec<u8>>; QUICK_SLOTS],
+
+    /// T-states and instructions since the machine started, for the rewind history
+    pub(super) clock: u64,
+    pub(super) instructions: u64,
+    pub(super) history: History,
+
+    machine_ctl_rx: mpsc::Receiver<MachineMessage>,
+    machine_ctl_tx: mpsc::Sender<MachineMessage>,
+}
+
+impl<M: Model> Spectrum<M> {
+    pub fn new(
+        frames: Box<dyn FrameSink>,
+        keys: mpsc::Receiver<KeyChange>,
+        machine_ctl_rx: mpsc::Receiver<MachineMessage>,
+        machine_ctl_tx: mpsc::Sender<MachineMessage>,
+        sound_tx: Option<mpsc::Sender<f32>>,
+    ) -> Self {
+        Self {
+            model: M::new(),
+            cpu: CPU::new(),
+            ula: ULA::new(frames, keys, sound_tx, M::TIMINGS),
+            machine_ctl_rx,
+            machine_ctl_tx,
+            deck: TapeDeck::new(M::MACHINE == MACHINE_48K),
+            tap_state: TapState::Empty,
+            tape_prompt: true,
+            slots: Default::default(),
+            clock: 0,
+            instructions: 0,
+            history: History::new(M::TIMINGS.frame_ts() as u64),
+        }
+    }
+
+    pub(crate) fn model_mut(&mut self) -> &mut M {
+        &mut self.model
+    }
+
+    /// Starts the tape deck when the ROM enters LD-BYTES, instead of trapping the loader.
+    pub fn set_tape_auto_start(&mut self, enabled: bool) {
+        self.deck.auto_start = enabled;
+    }
+
+    /// Runs flat out while a loader polls the EAR bit of the playing tape.
+    pub fn set_tape_fast_load(&mut self, enabled: bool) {
+        self.deck.fast_load = enabled;
+    }
+
+    /// Without a prompt the ROM loader just waits for a tape that never plays.
+    pub fn set_tape_prompt(&mut self, enabled: bool) {
+        self.tape_prompt = enabled;
+    }
+
+    /// Puts a .tap or .tzx in the deck, playing it when the deck auto-starts.
+    pub fn insert_tape(&mut self, path: &Path) -> Result<(), io::Error> {
+        self.deck.insert(Tap::new(path)?);
+        self.tap_state = TapState::Ready;
+        if self.deck.auto_start {
+            self.deck.play();
+        }
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::new(M::HARDWARE, self.cpu.regs);
+        snapshot.border = self.ula.border();
+        snapshot.tstates = self.ula.ts_in_frame();
+        snapshot.halted = self.cpu.halt;
+        if self.cpu.halt {
+            // snapshots keep PC on the HALT, the CPU has it past it
+            snapshot.regs.pc = snapshot.regs.pc.wrapping_sub(1);
+        }
+        // the ROM traps read the tape without playing it
+        snapshot.tape = self.deck.tap().map(|tap| TapeImage {
+            name: tap.name.clone(),
+            data: tap.data.clone(),
+            block: if self.deck.is_playing() {
+                self.deck.block()
+            } else {
+                tap.position()
+            },
+        });
+        self.model.snapshot(&mut snapshot);
+        snapshot
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.model.restore(snapshot);
+        self.cpu.set_registers(snapshot.regs);
+        self.ula.set_border(snapshot.border);
+        self.ula.set_ts_in_frame(snapshot.tstates);
+        self.cpu.halt = snapshot.halted;
+        if snapshot.halted {
+            self.cpu.regs.pc = self.cpu.regs.pc.wrapping_add(1);
+        }
+        if let Some(image) = snapshot.tape.as_ref() {
+            match image.to_tap() {
+                Ok(tap) => {
+                    self.deck.insert(tap);
+                    self.deck.seek(image.block);
+                    self.tap_state = TapState::Ready;
+                }
+                Err(e) => println!("Error loading snapshot tape {}: {}", image.name, e),
+            }
+        }
+    }
+
+    pub fn load_snapshot(&mut self, path: &Path) -> Result<(), io::Error> {
+        let snapshot = Snapshot::load(path)?;
+        self.restore(&snapshot);
+        Ok(())
+    }
+
+    pub fn save_snapshot(&mut self, path: &Path) -> Result<(), io::Error> {
+        self.finish_instruction();
+        self.snapshot().save(path)
+    }
+
+    /// Saves the last frame drawn, or the screen memory as a .scr.
+    pub fn save_screenshot(&self, path: &Path, options: ShotOptions) -> Result<(), io::Error> {
+        screenshot::save(path, self.ula.last_frame(), &self.screen(), options)
+    }
+
+    /// Saves the whole machine, even in the middle of an instruction.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.header(M::MACHINE);
+        self.write_state(&mut w);
+        w.into_inner()
+    }
+
+    /// Loads a state made by `save_state`, the machine is left untouched on error.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        let backup = self.save_state();
+        let result = self.read_state(data);
+        if result.is_err() {
+            self.read_state(&backup).unwrap();
+        }
+        result
+    }
+
+    pub fn quick_save(&mut self, slot: usize) {
+        if slot < QUICK_SLOTS {
+            self.slots[slot] = Some(self.save_state());
+        }
+    }
+
+    pub fn quick_load(&mut self, slot: usize) -> Result<(), io::Error> {
+        let Some(data) = self.slots.get(slot).cloned().flatten() else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "empty quick-save slot",
+            ));
+        };
+        self.load_state(&data)
+    }
+
+    fn write_state(&self, w: &mut StateWriter) {
+        self.cpu.save_state(w);
+        self.ula.save_state(w);
+        self.model.save_state(w);
+        self.deck.save_state(w);
+    }
+
+    fn read_state(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        let mut r = StateReader::new(data);
+        r.header(M::MACHINE)?;
+        let r = &mut r;
+        self.cpu.load_state(r)?;
+        self.ula.load_state(r)?;
+        self.model.load_state(r)?;
+        self.deck.load_state(r)?;
+        self.tap_state = if self.deck.tap().is_some() {
+            TapState::Ready
+        } else {
+            TapState::Empty
+        };
+        Ok(())
+    }
+
+    pub async fn run(&mut self) -> ! {
+        println!("{}::run()", M::NAME);
+        let mut interval = tokio::time::interval(Duration::from_millis(20));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            let fast = self.deck.is_fast_forwarding();
+            self.ula.set_turbo(fast);
+            if fast {
+                task::yield_now().await;
+            } else {
+                interval.tick().await;
+            }
+            for _ in 0..M::TIMINGS.frame_ts() {
+                self.tick();
+            }
+
+            if let Ok(msg) = self.machine_ctl_rx.try_recv() {
+                self.handle_message(msg);
+            }
+        }
+    }
+
+    fn handle_message(&mut self, msg: MachineMessage) {
+        // changes from outside happen between instructions and start a keyframe, so that
+        // the recorded input is enough to replay the history
+        let rewinding = matches!(msg, MachineMessage::Rewind(_) | MachineMessage::StepBack);
+        if !rewinding {
+            self.finish_instruction();
+        }
+        match msg {
+            MachineMessage::CPUWait => self.cpu.wait = true,
+            MachineMessage::CPUResume => self.cpu.wait = false,
+            MachineMessage::Reset => self.reset(),
+            MachineMessage::Nmi => self.cpu.pulse_nmi(),
+            MachineMessage::CPUSetRegisters(regs) => self.cpu.regs = regs,
+            MachineMessage::TapLoad(file) => {
+                if let Err(e) = self.insert_tape(&file) {
+                    println!("Error loading tape: {}", e);
+                }
+            }
+            MachineMessage::TapePlay => self.deck.play(),
+            MachineMessage::TapeStop => self.deck.stop(),
+            MachineMessage::TapeRewind => self.deck.rewind(),
+            MachineMessage::TapeFastLoad(enabled) => self.deck.fast_load = enabled,
+            MachineMessage::SnapshotLoad(file) => {
+                if let Err(e) = self.load_snapshot(&file) {
+                    println!("Error loading snapshot: {}", e);
+                }
+            }
+            MachineMessage::SnapshotSave(file) => {
+                if let Err(e) = self.save_snapshot(&file) {
+                    println!("Error saving snapshot: {}", e);
+                }
+            }
+            MachineMessage::ScreenshotSave(file, options) => {
+                if let Err(e) = self.save_screenshot(&file, options) {
+                    println!("Error saving screenshot: {}", e);
+                }
+            }
+            MachineMessage::QuickSave(slot) => self.quick_save(slot),
+            MachineMessage::QuickLoad(slot) => {
+                if let Err(e) = self.quick_load(slot) {
+                    println!("Error loading quick-save {}: {}", slot + 1, e);
+                }
+            }
+            MachineMessage::Rewind(secs) => {
+                if let Err(e) = self.rewind(secs) {
+                    println!("Error rewinding: {}", e);
+                }
+            }
+            MachineMessage::StepBack => {
+                if let Err(e) = self.step_back() {
+                    println!("Error stepping back: {}", e);
+                }
+            }
+        }
+        if !rewinding {
+            self.keyframe();
+        }
+    }
+
+    /// Runs one T-state of the whole machine, returns the CPU instruction boundary.
+    pub(super) fn tick(&mut self) -> Option<u16> {
+        self.ula.set_ear(self.deck.tick());
+        self.ula.tick();
+        self.ula_bus_tick();
+        self.ula.tick();
+        self.ula_bus_tick();
+        if self.ula.take_keys_changed() {
+            self.history.record_input(self.clock, self.ula.keyboard());
+        }
+
+        let trap = self.cpu.tick_with(&mut self.model.bus(&mut self.ula));
+
+        if let SignalReq::Read = self.cpu.signals.port {
+            if self.cpu.signals.addr & 0x0001 == 0x0000 {
+                self.deck.on_ear_read();
+            }
+        }
+        if let Some(pc) = trap {
+            self.deck.on_instruction(pc);
+        }
+
+        self.model.tick(&mut self.ula);
+
+        match trap {
+            // LD-BYTES only lives in the 48K BASIC ROM
+            Some(0x0556) if self.model.basic_rom() => self.deck.on_ld_bytes(),
+            // the trap only loads when the tape is not being played
+            Some(0x056B) if !self.deck.is_playing() && self.model.basic_rom() => {
+                self.ula.clean_keyboard();
+
+                match self.tap_state {
+                    TapState::Empty if self.tape_prompt => {
+                        self.tap_state = TapState::Loading;
+                        load_tap_file(self.machine_ctl_tx.clone());
+                    }
+                    TapState::Empty | TapState::Loading => (),
+                    TapState::Ready => self.load_tap_block(),
+                }
+            }
+            _ => {}
+        }
+
+        self.clock += 1;
+        if trap.is_some() {
+            self.instructions += 1;
+            if self.history.is_due(self.clock) {
+                self.keyframe();
+            }
+        }
+        trap
+    }
+
+    /// Runs until the CPU is between instructions, so its state can be saved.
+    fn finish_instruction(&mut self) {
+        for _ in 0..100 {
+            if self.tick().is_some() {
+                return;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.cpu.do_reset = true;
+        self.model.reset();
+        self.deck.eject();
+        self.tap_state = TapState::Empty;
+    }
+
+    fn ula_bus_tick(&mut self) {
+        match self.ula.signals.mem {
+            SignalReq::Read => {
+                self.ula.signals.data = self.model.screen_read(self.ula.signals.addr)
+            }
+            SignalReq::Write | SignalReq::Ack | SignalReq::None => (),
+        }
+    }
+
+    fn load_tap_block(&mut self) {
+        let data: Vec<u8> = match self.deck.tap_mut() {
+            Some(tap) => tap.next_block().unwrap_or_else(Vec::new),
+            None => Vec::new(),
+        };
+        // playing the tape carries on after the block loaded
+        if let Some(position) = self.deck.tap().map(|tap| tap.position()) {
+            self.deck.seek(position);
+        }
+        if data.is_empty() {
+            return;
+        }
+
+        let requested_length = self.cpu.regs.de() as usize;
+        let start_address = self.cpu.regs.ix();
+
+        // data has the flag byte, the bytes and the checksum, a short block runs out early
+        let loaded = if self.cpu.regs.a_alt == data[0] {
+            if self.cpu.regs.f_alt.c {
+                let length = requested_length.min(data.len() - 1);
+                for (i, byte) in data[1..=length].iter().enumerate() {
+                    self.model
+                        .write(start_address.wrapping_add(i as u16), *byte);
+                }
+            }
+            // the flag, the bytes asked for and the checksum XOR to 0
+            let parity = data
+                .iter()
+                .take(requested_length + 2)
+                .fold(0, |parity, byte| parity ^ byte);
+            data.len() >= requested_length + 2 && parity == 0
+        } else {
+            false
+        };
+        // the ROM routine returns with carry set when the block loaded
+        self.cpu.regs.f.c = loaded;
+
+        self.cpu.regs.pc = 0x05e2;
+    }
+}
+
+impl<M: Model> Machine for Spectrum<M> {
+    fn step(&mut self) -> Option<u16> {
+        self.tick()
+    }
+
+    fn ula(&mut self) -> &mut ULA {
+        &mut self.ula
+    }
+
+    fn regs(&self) -> Registers {
+        self.cpu.regs
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.model.read(addr)
+    }
+
+    fn screen(&self) -> Vec<u8> {
+        self.model.screen()[..SCR_SIZE].to_vec()
+    }
+}
+
+pub(crate) fn load_tap_file(machine_ctl_tx: mpsc::Sender<MachineMessage>) {
+    let _ = task::spawn(async move {
+        let path: PathBuf = env::current_dir().unwrap();
+        let file: Option<_> = FileDialog::new()
+            .add_filter("tape", &["tap", "tzx"])
+            .set_directory(path)
+            .pick_file();
+        match file {
+            Some(f) => machine_ctl_tx.send(MachineMessage::TapLoad(f)).unwrap(),
+            None => machine_ctl_tx.send(MachineMessage::Reset).unwrap(),
+        }
+    });
+}
+
+/// Picks a snapshot to load in the machine.
+pub fn load_snapshot_file(machine_ctl_tx: mpsc::Sender<MachineMessage>) {
+    let _ = task::spawn(async move {
+        let path: PathBuf = env::current_dir().unwrap();
+        let file: Option<_> = FileDialog::new()
+            .add_filter("snapshot", &EXTENSIONS)
+            .set_directory(path)
+            .pick_file();
+        if let Some(f) = file {
+            let _ = machine_ctl_tx.send(MachineMessage::SnapshotLoad(f));
+        }
+    });
+}
+
+/// Picks where to save a snapshot of the machine.
+pub fn save_snapshot_file(machine_ctl_tx: mpsc::Sender<MachineMessage>) {
+    let _ = task::spawn(async move {
+        let path: PathBuf = env::current_dir().unwrap();
+        let file: Option<_> = FileDialog::new()
+            .add_filter("snapshot", &EXTENSIONS)
+            .set_directory(path)
+            .save_file();
+        if let Some(f) = file {
+            let _ = machine_ctl_tx.send(MachineMessage::SnapshotSave(f));
+        }
+    });
+}
+
+/// Picks where to save a screenshot of the machine.
+pub fn save_screenshot_file(machine_ctl_tx: mpsc::Sender<MachineMessage>, options: ShotOptions) {
+    let _ = task::spawn(async move {
+        let path: PathBuf = env::current_dir().unwrap();
+        let file: Option<_> = FileDialog::new()
+            .add_filter("PNG image", &["png"])
+            .add_filter("screen memory", &["scr"])
+            .set_directory(path)
+            .save_file();
+        if let Some(f) = file {
+            let _ = machine_ctl_tx.send(MachineMessage::ScreenshotSave(f, options));
+        }
+    });
+}
@@ -0,0 +1,155 @@
+use std::{
+    env,
+    fs::File,
+    io::{Error, Read},
+};
+
+use crate::state::{SaveState, StateReader, StateWriter};
+
+pub const BANK_SIZE: usize = 0x4000;
+
+pub type Bank = [u8; BANK_SIZE];
+
+/// 128K memory map: two ROMs, eight RAM pages and the 0x7FFD paging register.
+///
+/// 0x0000 - selected ROM
+/// 0x4000 - RAM page 5
+/// 0x8000 - RAM page 2
+/// 0xC000 - RAM page selected by bits 0-2 of 0x7FFD
+pub struct Memory128k {
+    roms: [Bank; 2],
+    ram: Vec<Bank>,
+    port_7ffd: u8,
+}
+
+impl Default for Memory128k {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory128k {
+    pub fn new() -> Self {
+        Self {
+            roms: [load_rom("128-0.rom"), load_rom("128-1.rom")],
+            ram: vec![[0; BANK_SIZE]; 8],
+            port_7ffd: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.port_7ffd = 0;
+    }
+
+    pub fn port_7ffd(&self) -> u8 {
+        self.port_7ffd
+    }
+
+    /// Writes to the paging port are ignored once bit 5 (paging lock) is set.
+    pub fn write_7ffd(&mut self, data: u8) {
+        if !self.is_locked() {
+            self.port_7ffd = data;
+        }
+    }
+
+    /// Sets the paging register ignoring the lock, to restore a snapshot.
+    pub fn set_port_7ffd(&mut self, data: u8) {
+        self.port_7ffd = data;
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.port_7ffd & 0x20 != 0
+    }
+
+    /// ROM mapped at 0x0000: 0 is the 128K editor, 1 the 48K BASIC.
+    pub fn rom(&self) -> usize {
+        ((self.port_7ffd >> 4) & 0x01) as usize
+    }
+
+    /// RAM page mapped at 0xC000.
+    pub fn ram_page(&self) -> usize {
+        (self.port_7ffd & 0x07) as usize
+    }
+
+    /// RAM page the ULA displays: 5 for the normal screen, 7 for the shadow one.
+    pub fn screen_page(&self) -> usize {
+        if self.port_7ffd & 0x08 != 0 {
+            7
+        } else {
+            5
+        }
+    }
+
+    pub fn page(&self, page: usize) -> &Bank {
+        &self.ram[page]
+    }
+
+    pub fn page_mut(&mut self, page: usize) -> &mut Bank {
+        &mut self.ram[page]
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        let offset = (addr & 0x3fff) as usize;
+        match addr >> 14 {
+            0 => self.roms[self.rom()][offset],
+            1 => self.ram[5][offset],
+            2 => self.ram[2][offset],
+            _ => self.ram[self.ram_page()][offset],
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        let offset = (addr & 0x3fff) as usize;
+        match addr >> 14 {
+            0 => (),
+            1 => self.ram[5][offset] = data,
+            2 => self.ram[2][offset] = data,
+            _ => {
+                let page = self.ram_page();
+                self.ram[page][offset] = data;
+            }
+        }
+    }
+
+    /// ULA access: the screen is always fetched from the page selected by bit 3 of 0x7FFD.
+    pub fn screen_read(&self, addr: u16) -> u8 {
+        self.ram[self.screen_page()][(addr & 0x3fff) as usize]
+    }
+
+    /// On the 128K the odd RAM pages share the bus with the ULA.
+    pub fn is_contended(&self, addr: u16) -> bool {
+        match addr >> 14 {
+            1 => true,
+            3 => self.ram_page() & 0x01 == 0x01,
+            _ => false,
+        }
+    }
+}
+
+/// The ROMs are not saved, they are loaded from `bin/`.
+impl SaveState for Memory128k {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.port_7ffd);
+        for page in &self.ram {
+            w.raw(page);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.port_7ffd = r.u8()?;
+        for page in self.ram.iter_mut() {
+            page.copy_from_slice(r.raw(BANK_SIZE)?);
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn load_rom(name: &str) -> Bank {
+    let path = env::current_dir().unwrap().join("bin").join(name);
+
+    let mut f = File::open(&path).expect("Failed to open ROM file");
+    let mut rom = [0; BANK_SIZE];
+    f.read_exact(&mut rom).expect("Failed to read ROM file");
+
+    rom
+}
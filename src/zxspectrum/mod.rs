@@ -1,3 +1,18 @@
+pub mod ay;
+pub mod deck;
+pub mod headless;
+pub mod machine;
+pub mod memory;
+pub mod rewind;
+pub mod screenshot;
+pub mod sna;
+pub mod snapshot;
+pub mod szx;
 pub mod tap;
 pub mod ula;
+pub mod z80snap;
+pub mod zx128k;
 pub mod zx48k;
+
+#[cfg(test)]
+mod tests;
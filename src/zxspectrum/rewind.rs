@@ -0,0 +1,235 @@
+use std::collections::VecDeque;
+use std::io;
+
+use super::machine::{Model, Spectrum};
+
+/// Frames between keyframes.
+const KEYFRAME_FRAMES: u64 = 25;
+/// Seconds of history kept.
+const HISTORY_SECONDS: u64 = 60;
+const FRAMES_PER_SECOND: u64 = 50;
+/// Equal bytes allowed inside a delta run before it is split.
+const RUN_GAP: usize = 8;
+
+/// Machine state saved at an instruction boundary.
+#[derive(Debug, Clone, Copy)]
+pub struct Keyframe {
+    /// T-states since the machine started.
+    pub clock: u64,
+    /// Instructions since the machine started.
+    pub instructions: u64,
+    /// Keyboard rows, the keyboard is not part of a save state.
+    pub keyboard: [u8; 8],
+}
+
+/// (clock, keyboard rows) of a key change.
+pub type Input = (u64, [u8; 8]);
+
+enum Frame {
+    Full(Vec<u8>),
+    /// (offset, bytes) that differ from the next keyframe.
+    Delta(Vec<(usize, Vec<u8>)>),
+}
+
+/// Rewind history: periodic save states and the key changes between them.
+///
+/// Only the newest save state is kept whole, the older ones are stored as the bytes that
+/// differ from the next one, so most of the RAM is only stored once. Any point of the
+/// history can be reached again by loading the keyframe before it and replaying the
+/// recorded input.
+pub struct History {
+    keyframes: VecDeque<(Keyframe, Frame)>,
+    inputs: VecDeque<Input>,
+    frame_ts: u64,
+    next: u64,
+}
+
+impl History {
+    pub fn new(frame_ts: u64) -> Self {
+        Self {
+            keyframes: VecDeque::new(),
+            inputs: VecDeque::new(),
+            frame_ts,
+            next: 0,
+        }
+    }
+
+    /// T-states in `secs` seconds.
+    pub fn seconds(&self, secs: u32) -> u64 {
+        secs as u64 * FRAMES_PER_SECOND * self.frame_ts
+    }
+
+    pub fn is_due(&self, clock: u64) -> bool {
+        clock >= self.next
+    }
+
+    pub fn len(&self) -> usize {
+        self.keyframes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keyframes.is_empty()
+    }
+
+    pub fn push(&mut self, keyframe: Keyframe, state: Vec<u8>) {
+        if let Some((_, frame)) = self.keyframes.back_mut() {
+            if let Frame::Full(previous) = frame {
+                *frame = diff(previous, &state);
+            }
+        }
+        self.keyframes.push_back((keyframe, Frame::Full(state)));
+        self.next = keyframe.clock + KEYFRAME_FRAMES * self.frame_ts;
+
+        let capacity = (HISTORY_SECONDS * FRAMES_PER_SECOND / KEYFRAME_FRAMES) as usize;
+        while self.keyframes.len() > capacity {
+            self.keyframes.pop_front();
+        }
+        if let Some((oldest, _)) = self.keyframes.front() {
+            while self
+                .inputs
+                .front()
+                .is_some_and(|(clock, _)| *clock < oldest.clock)
+            {
+                self.inputs.pop_front();
+            }
+        }
+    }
+
+    pub fn record_input(&mut self, clock: u64, keyboard: [u8; 8]) {
+        self.inputs.push_back((clock, keyboard));
+    }
+
+    /// Newest keyframe at or before `clock`, or the oldest one.
+    pub fn before_clock(&self, clock: u64) -> Option<usize> {
+        self.position(|keyframe| keyframe.clock <= clock)
+    }
+
+    /// Newest keyframe at or before `instructions`, or the oldest one.
+    pub fn before_instruction(&self, instructions: u64) -> Option<usize> {
+        self.position(|keyframe| keyframe.instructions <= instructions)
+    }
+
+    fn position(&self, f: impl Fn(&Keyframe) -> bool) -> Option<usize> {
+        if self.keyframes.is_empty() {
+            return None;
+        }
+        let newest = self.keyframes.iter().rposition(|(keyframe, _)| f(keyframe));
+        Some(newest.unwrap_or(0))
+    }
+
+    /// Goes back to keyframe `index`, dropping the newer ones. Returns the keyframe, its
+    /// save state and the input to replay from there.
+    pub fn restore(&mut self, index: usize) -> (Keyframe, Vec<u8>, Vec<Input>) {
+        let mut state = Vec::new();
+        for (_, frame) in self.keyframes.iter().skip(index).rev() {
+            state = match frame {
+                Frame::Full(full) => full.clone(),
+                Frame::Delta(runs) => patch(&state, runs),
+            };
+        }
+        self.keyframes.truncate(index + 1);
+        let keyframe = self.keyframes[index].0;
+        self.keyframes[index].1 = Frame::Full(state.clone());
+        self.next = keyframe.clock + KEYFRAME_FRAMES * self.frame_ts;
+
+        let inputs = self
+            .inputs
+            .iter()
+            .filter(|(clock, _)| *clock >= keyframe.clock)
+            .copied()
+            .collect();
+        (keyframe, state, inputs)
+    }
+
+    /// Forgets the input from `clock` on, once the machine has been rewound there.
+    pub fn truncate(&mut self, clock: u64) {
+        while self.inputs.back().is_some_and(|(c, _)| *c >= clock) {
+            self.inputs.pop_back();
+        }
+    }
+}
+
+/// Going back in time, by replaying the history from a keyframe.
+impl<M: Model> Spectrum<M> {
+    /// Goes back `secs` seconds, or as far as the history goes.
+    pub fn rewind(&mut self, secs: u32) -> Result<(), io::Error> {
+        let target = self.clock.saturating_sub(self.history.seconds(secs));
+        match self.history.before_clock(target) {
+            Some(index) => self.replay(index, |zx| zx.clock >= target),
+            None => Ok(()),
+        }
+    }
+
+    /// Goes back to the start of the previous instruction.
+    pub fn step_back(&mut self) -> Result<(), io::Error> {
+        let target = self.instructions.saturating_sub(1);
+        match self.history.before_instruction(target) {
+            Some(index) => self.replay(index, |zx| zx.instructions >= target),
+            None => Ok(()),
+        }
+    }
+
+    /// Loads keyframe `index` and runs the recorded input until `done`, the history after
+    /// that point is lost.
+    fn replay(&mut self, index: usize, done: impl Fn(&Self) -> bool) -> Result<(), io::Error> {
+        let (keyframe, state, inputs) = self.history.restore(index);
+        self.load_state(&state)?;
+        self.clock = keyframe.clock;
+        self.instructions = keyframe.instructions;
+        self.ula.set_keyboard(keyframe.keyboard);
+
+        self.ula.set_live_input(false);
+        self.ula.set_turbo(true);
+        let mut inputs = inputs.into_iter().peekable();
+        while !done(self) {
+            while let Some((_, keyboard)) = inputs.next_if(|(clock, _)| *clock <= self.clock) {
+                self.ula.set_keyboard(keyboard);
+            }
+            self.tick();
+        }
+        self.ula.set_live_input(true);
+        self.history.truncate(self.clock);
+        Ok(())
+    }
+
+    pub(super) fn keyframe(&mut self) {
+        let keyframe = Keyframe {
+            clock: self.clock,
+            instructions: self.instructions,
+            keyboard: self.ula.keyboard(),
+        };
+        let state = self.save_state();
+        self.history.push(keyframe, state);
+    }
+}
+
+/// Runs of `old` that differ from `new`, `old` is stored whole if the sizes differ.
+fn diff(old: &[u8], new: &[u8]) -> Frame {
+    if old.len() != new.len() {
+        return Frame::Full(old.to_vec());
+    }
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < old.len() {
+        if old[i] == new[i] {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        let mut same = 0;
+        while i < old.len() && same < RUN_GAP {
+            same = if old[i] == new[i] { same + 1 } else { 0 };
+            i += 1;
+        }
+        runs.push((start, old[start..i - same].to_vec()));
+    }
+    Frame::Delta(runs)
+}
+
+fn patch(new: &[u8], runs: &[(usize, Vec<u8>)]) -> Vec<u8> {
+    let mut old = new.to_vec();
+    for (start, bytes) in runs {
+        old[*start..*start + bytes.len()].copy_from_slice(bytes);
+    }
+    old
+}
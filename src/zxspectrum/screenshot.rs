@@ -0,0 +1,134 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Write};
+use std::path::Path;
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use super::ula::{SCREEN_BORDER, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Bitmap and attributes, 0x4000 to 0x5AFF.
+pub const SCR_SIZE: usize = 6912;
+
+const PNG_MAGIC: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// How the frame is turned into an image, a .scr is always the raw screen memory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShotOptions {
+    pub border: bool,
+    /// 1 or 2, pixels are repeated.
+    pub scale: usize,
+}
+
+impl Default for ShotOptions {
+    fn default() -> Self {
+        Self {
+            border: true,
+            scale: 1,
+        }
+    }
+}
+
+/// Saves a screenshot, the format is taken from the file extension. `pixels` is the RGBA
+/// frame of the ULA, `screen` the screen memory it shows.
+pub fn save(path: &Path, pixels: &[u8], screen: &[u8], options: ShotOptions) -> Result<(), Error> {
+    let data = match extension(path).as_str() {
+        "png" => to_png(pixels, options)?,
+        "ppm" => to_ppm(pixels, options),
+        "scr" => screen[..SCR_SIZE].to_vec(),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "unknown screenshot format",
+            ))
+        }
+    };
+    fs::write(path, data)
+}
+
+/// (width, height, RGB rows) of the frame.
+pub fn to_rgb(pixels: &[u8], options: ShotOptions) -> (usize, usize, Vec<u8>) {
+    let (x0, y0, width, height) = if options.border {
+        (0, 0, SCREEN_WIDTH, SCREEN_HEIGHT)
+    } else {
+        (SCREEN_BORDER, SCREEN_BORDER, 256, 192)
+    };
+    let scale = options.scale;
+    let mut rgb = Vec::with_capacity(width * height * scale * scale * 3);
+    for y in y0..y0 + height {
+        let start = rgb.len();
+        for x in x0..x0 + width {
+            let i = (x + y * SCREEN_WIDTH) * 4;
+            for _ in 0..scale {
+                rgb.extend_from_slice(&pixels[i..i + 3]);
+            }
+        }
+        for _ in 1..scale {
+            rgb.extend_from_within(start..);
+        }
+    }
+    (width * scale, height * scale, rgb)
+}
+
+pub fn to_png(pixels: &[u8], options: ShotOptions) -> Result<Vec<u8>, Error> {
+    let (width, height, rgb) = to_rgb(pixels, options);
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits RGB, deflate, no filters, not interlaced
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    for row in rgb.chunks_exact(width * 3) {
+        // filter type None
+        encoder.write_all(&[0])?;
+        encoder.write_all(row)?;
+    }
+    let data = encoder.finish()?;
+
+    let mut png = PNG_MAGIC.to_vec();
+    chunk(&mut png, b"IHDR", &header);
+    chunk(&mut png, b"IDAT", &data);
+    chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+/// Binary PPM, handy to diff in tests.
+pub fn to_ppm(pixels: &[u8], options: ShotOptions) -> Vec<u8> {
+    let (width, height, rgb) = to_rgb(pixels, options);
+    let mut ppm = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    ppm.extend_from_slice(&rgb);
+    ppm
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase()
+}
@@ -0,0 +1,153 @@
+use std::io::{Error, ErrorKind};
+
+use crate::z80::registers::Registers;
+
+use super::memory::{Bank, BANK_SIZE};
+use super::snapshot::{Hardware, Snapshot};
+
+const HEADER_LEN: usize = 27;
+const SNA_48K_LEN: usize = HEADER_LEN + 3 * BANK_SIZE;
+/// 128K snapshot: the 48K image plus PC, 0x7FFD, TR-DOS flag and the other five pages.
+const SNA_128K_LEN: usize = SNA_48K_LEN + 4 + 5 * BANK_SIZE;
+/// Same, when page 2 or 5 is also paged at 0xC000 and six pages follow.
+const SNA_128K_LONG_LEN: usize = SNA_128K_LEN + BANK_SIZE;
+
+/// .sna snapshots
+///
+/// 48K: 27 bytes of registers followed by the RAM, PC is on the stack.
+/// 128K: the 48K image (with the page at 0xC000), then PC, 0x7FFD, the TR-DOS flag
+/// and the remaining RAM pages in ascending order.
+impl Snapshot {
+    pub fn from_sna(data: &[u8]) -> Result<Self, Error> {
+        let is_128k = match data.len() {
+            SNA_48K_LEN => false,
+            SNA_128K_LEN | SNA_128K_LONG_LEN => true,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "invalid SNA length")),
+        };
+
+        let word = |i: usize| (data[i] as u16) | ((data[i + 1] as u16) << 8);
+        let bank = |i: usize| -> Bank {
+            let start = HEADER_LEN + i * BANK_SIZE;
+            data[start..start + BANK_SIZE].try_into().unwrap()
+        };
+
+        let mut regs = Registers::new();
+        regs.i = data[0];
+        regs.set_all_regs([
+            word(21),
+            word(13),
+            word(11),
+            word(9),
+            word(7),
+            word(5),
+            word(3),
+            word(1),
+            word(17),
+            word(15),
+            word(23),
+            0,
+        ]);
+        regs.iff2 = data[19] & 0x04 != 0;
+        regs.iff1 = regs.iff2;
+        regs.r = data[20];
+        regs.im = data[25] & 0x03;
+
+        let hardware = if is_128k {
+            Hardware::Zx128k
+        } else {
+            Hardware::Zx48k
+        };
+        let mut snapshot = Self::new(hardware, regs);
+        snapshot.border = data[26] & 0x07;
+
+        if is_128k {
+            let extra = SNA_48K_LEN;
+            snapshot.regs.pc = word(extra);
+            snapshot.port_7ffd = data[extra + 2];
+
+            let paged = (snapshot.port_7ffd & 0x07) as usize;
+            // page 2 or 5 at 0xC000 leaves six pages to follow, any other five
+            let expected = match paged {
+                2 | 5 => SNA_128K_LONG_LEN,
+                _ => SNA_128K_LEN,
+            };
+            if data.len() != expected {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "SNA length does not match the paged bank",
+                ));
+            }
+            snapshot.ram[5] = bank(0);
+            snapshot.ram[2] = bank(1);
+            snapshot.ram[paged] = bank(2);
+
+            let mut next = 0..;
+            for page in [0, 1, 3, 4, 6, 7] {
+                if page != paged {
+                    let start = extra + 4 + next.next().unwrap() * BANK_SIZE;
+                    snapshot.ram[page] = data[start..start + BANK_SIZE].try_into().unwrap();
+                }
+            }
+        } else {
+            snapshot.ram = vec![bank(0), bank(1), bank(2)];
+            // the snapshot was taken as if inside an interrupt: PC is popped with a RETN
+            let sp = snapshot.regs.sp;
+            snapshot.regs.pc =
+                (snapshot.read(sp) as u16) | ((snapshot.read(sp.wrapping_add(1)) as u16) << 8);
+            snapshot.regs.sp = sp.wrapping_add(2);
+        }
+
+        Ok(snapshot)
+    }
+
+    pub fn to_sna(&self) -> Vec<u8> {
+        let mut snapshot = self.clone();
+        if !self.is_128k() {
+            let sp = snapshot.regs.sp.wrapping_sub(2);
+            snapshot.write(sp, snapshot.regs.pc as u8);
+            snapshot.write(sp.wrapping_add(1), (snapshot.regs.pc >> 8) as u8);
+            snapshot.regs.sp = sp;
+        }
+        let regs = &snapshot.regs;
+
+        let mut data = Vec::with_capacity(SNA_128K_LONG_LEN);
+        data.push(regs.i);
+        for word in [
+            regs.hl_aux(),
+            regs.de_aux(),
+            regs.bc_aux(),
+            regs.af_aux(),
+            regs.hl(),
+            regs.de(),
+            regs.bc(),
+            regs.iy(),
+            regs.ix(),
+        ] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        data.push(if regs.iff2 { 0x04 } else { 0x00 });
+        data.push(regs.r);
+        data.extend_from_slice(&regs.af().to_le_bytes());
+        data.extend_from_slice(&regs.sp.to_le_bytes());
+        data.push(regs.im);
+        data.push(snapshot.border);
+
+        for addr in [0x4000, 0x8000, 0xc000] {
+            data.extend_from_slice(snapshot.bank(addr));
+        }
+
+        if snapshot.is_128k() {
+            let paged = (snapshot.port_7ffd & 0x07) as usize;
+            data.extend_from_slice(&regs.pc.to_le_bytes());
+            data.push(snapshot.port_7ffd);
+            data.push(0);
+            for page in [0, 1, 3, 4, 6, 7] {
+                if page != paged {
+                    data.extend_from_slice(&snapshot.ram[page]);
+                }
+            }
+        }
+
+        data
+    }
+}
@@ -0,0 +1,165 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+use crate::z80::registers::Registers;
+
+use super::memory::{Bank, BANK_SIZE};
+use super::tap::Tap;
+
+/// File extensions of the supported snapshot formats.
+pub const EXTENSIONS: [&str; 3] = ["sna", "z80", "szx"];
+
+/// Machine the snapshot was taken on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hardware {
+    Zx48k,
+    Zx128k,
+    /// +2A/+3, restored as a 128K: the 0x1FFD paging modes are not emulated.
+    Plus3,
+    /// Pentagon 128, restored as a 128K.
+    Pentagon,
+}
+
+/// Machine state shared by the snapshot formats.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub hardware: Hardware,
+    pub regs: Registers,
+    pub border: u8,
+    /// RAM banks: 3 on a 48K (0x4000, 0x8000 and 0xC000), 8 pages on the others.
+    pub ram: Vec<Bank>,
+    /// Last value written to the 0x7FFD paging port (128K only).
+    pub port_7ffd: u8,
+    /// AY registers, when the machine has one.
+    pub ay_regs: Option<[u8; 16]>,
+    pub ay_selected: u8,
+    /// T-states since the start of the frame.
+    pub tstates: u32,
+    /// The CPU is executing a HALT, PC points to it.
+    pub halted: bool,
+    pub tape: Option<TapeImage>,
+}
+
+/// Tape in the deck when the snapshot was taken.
+#[derive(Debug, Clone)]
+pub struct TapeImage {
+    /// File name, used to open the tape again when `data` is empty.
+    pub name: String,
+    pub data: Vec<u8>,
+    /// Block to play next.
+    pub block: usize,
+}
+
+impl Snapshot {
+    pub fn new(hardware: Hardware, regs: Registers) -> Self {
+        let banks = if hardware == Hardware::Zx48k { 3 } else { 8 };
+        Self {
+            hardware,
+            regs,
+            border: 0,
+            ram: vec![[0; BANK_SIZE]; banks],
+            port_7ffd: 0,
+            ay_regs: None,
+            ay_selected: 0,
+            tstates: 0,
+            halted: false,
+            tape: None,
+        }
+    }
+
+    pub fn is_128k(&self) -> bool {
+        self.hardware != Hardware::Zx48k
+    }
+
+    /// RAM bank mapped at `addr` (0x4000 and up).
+    pub(crate) fn bank(&self, addr: u16) -> &Bank {
+        let slot = (addr >> 14) as usize;
+        if self.is_128k() {
+            match slot {
+                1 => &self.ram[5],
+                2 => &self.ram[2],
+                _ => &self.ram[(self.port_7ffd & 0x07) as usize],
+            }
+        } else {
+            &self.ram[slot - 1]
+        }
+    }
+
+    fn bank_mut(&mut self, addr: u16) -> &mut Bank {
+        let slot = (addr >> 14) as usize;
+        if self.is_128k() {
+            let page = match slot {
+                1 => 5,
+                2 => 2,
+                _ => (self.port_7ffd & 0x07) as usize,
+            };
+            &mut self.ram[page]
+        } else {
+            &mut self.ram[slot - 1]
+        }
+    }
+
+    /// Reads RAM as the CPU would see it, the ROM is not part of the snapshot.
+    pub fn read(&self, addr: u16) -> u8 {
+        if addr < 0x4000 {
+            return 0xff;
+        }
+        self.bank(addr)[(addr & 0x3fff) as usize]
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        if addr >= 0x4000 {
+            self.bank_mut(addr)[(addr & 0x3fff) as usize] = data;
+        }
+    }
+
+    /// Loads a snapshot, the format is taken from the file extension.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let mut file = File::open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        match extension(path).as_str() {
+            "sna" => Self::from_sna(&data),
+            "z80" => Self::from_z80(&data),
+            "szx" => Self::from_szx(&data),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "unknown snapshot format",
+            )),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let data = match extension(path).as_str() {
+            "sna" => self.to_sna(),
+            "z80" => self.to_z80(),
+            "szx" => self.to_szx()?,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "unknown snapshot format",
+                ))
+            }
+        };
+        File::create(path)?.write_all(&data)
+    }
+}
+
+impl TapeImage {
+    pub fn to_tap(&self) -> Result<Tap, Error> {
+        if self.data.is_empty() {
+            Tap::new(Path::new(&self.name))
+        } else {
+            Tap::from_data(self.data.clone(), self.name.clone())
+        }
+    }
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase()
+}
@@ -0,0 +1,338 @@
+use std::io::{Error, ErrorKind, Read, Write};
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use crate::z80::registers::Registers;
+
+use super::memory::{Bank, BANK_SIZE};
+use super::snapshot::{Hardware, Snapshot, TapeImage};
+
+const MAGIC: &[u8; 4] = b"ZXST";
+const VERSION: (u8, u8) = (1, 4);
+
+const MACHINE_16K: u8 = 0;
+const MACHINE_48K: u8 = 1;
+const MACHINE_128K: u8 = 2;
+const MACHINE_PLUS2: u8 = 3;
+const MACHINE_PLUS2A: u8 = 4;
+const MACHINE_PLUS3: u8 = 5;
+const MACHINE_PLUS3E: u8 = 6;
+const MACHINE_PENTAGON128: u8 = 7;
+const MACHINE_NTSC48K: u8 = 15;
+
+const Z80R_HALTED: u8 = 0x02;
+const RAMP_COMPRESSED: u16 = 0x01;
+const AY_128K_ON_48K: u8 = 0x02;
+const TAPE_EMBEDDED: u16 = 0x01;
+const TAPE_COMPRESSED: u16 = 0x02;
+const JOYSTICK_NONE: u8 = 8;
+
+/// .szx (zx-state) snapshots
+///
+/// An 8 byte header followed by blocks of `id (4 chars) + size (u32) + data`. Only the
+/// blocks for the emulated hardware are read, the others are skipped.
+impl Snapshot {
+    pub fn from_szx(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < 8 || &data[0..4] != MAGIC {
+            return Err(invalid_data("not a SZX snapshot"));
+        }
+        let version = (data[4], data[5]);
+        let hardware = match data[6] {
+            MACHINE_16K | MACHINE_48K | MACHINE_NTSC48K => Hardware::Zx48k,
+            MACHINE_128K | MACHINE_PLUS2 => Hardware::Zx128k,
+            MACHINE_PLUS2A | MACHINE_PLUS3 | MACHINE_PLUS3E => Hardware::Plus3,
+            MACHINE_PENTAGON128 => Hardware::Pentagon,
+            machine => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("unsupported SZX machine {}", machine),
+                ))
+            }
+        };
+        let mut snapshot = Self::new(hardware, Registers::new());
+
+        let mut pos = 8;
+        while pos + 8 <= data.len() {
+            let id = &data[pos..pos + 4];
+            let size = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            let body = data
+                .get(pos + 8..pos + 8 + size)
+                .ok_or_else(|| invalid_data("truncated SZX block"))?;
+            pos += 8 + size;
+
+            match id {
+                b"Z80R" => snapshot.read_z80r(body, version)?,
+                b"SPCR" => {
+                    let body = expect_len(body, 8)?;
+                    snapshot.border = body[0] & 0x07;
+                    snapshot.port_7ffd = body[1];
+                }
+                b"RAMP" => snapshot.read_ramp(body)?,
+                b"AY\0\0" => {
+                    let body = expect_len(body, 18)?;
+                    let mut regs = [0; 16];
+                    regs.copy_from_slice(&body[2..18]);
+                    snapshot.ay_regs = Some(regs);
+                    snapshot.ay_selected = body[1] & 0x0f;
+                }
+                b"TAPE" => snapshot.tape = Some(read_tape(body)?),
+                // the keyboard block only has the issue 2 and joystick settings
+                _ => (),
+            }
+        }
+
+        Ok(snapshot)
+    }
+
+    pub fn to_szx(&self) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        data.extend_from_slice(MAGIC);
+        data.push(VERSION.0);
+        data.push(VERSION.1);
+        data.push(match self.hardware {
+            Hardware::Zx48k => MACHINE_48K,
+            Hardware::Zx128k => MACHINE_128K,
+            Hardware::Plus3 => MACHINE_PLUS3,
+            Hardware::Pentagon => MACHINE_PENTAGON128,
+        });
+        data.push(0);
+
+        write_block(&mut data, b"Z80R", &self.z80r());
+
+        let mut spcr = vec![0; 8];
+        spcr[0] = self.border;
+        spcr[1] = if self.is_128k() { self.port_7ffd } else { 0 };
+        spcr[3] = self.border;
+        write_block(&mut data, b"SPCR", &spcr);
+
+        let pages: Vec<(u8, &Bank)> = if self.is_128k() {
+            self.ram
+                .iter()
+                .enumerate()
+                .map(|(page, bank)| (page as u8, bank))
+                .collect()
+        } else {
+            vec![(5, &self.ram[0]), (2, &self.ram[1]), (0, &self.ram[2])]
+        };
+        for (page, bank) in pages {
+            let mut ramp = Vec::new();
+            ramp.extend_from_slice(&RAMP_COMPRESSED.to_le_bytes());
+            ramp.push(page);
+            ramp.extend_from_slice(&deflate(bank)?);
+            write_block(&mut data, b"RAMP", &ramp);
+        }
+
+        if let Some(regs) = self.ay_regs {
+            let mut ay = Vec::with_capacity(18);
+            ay.push(if self.is_128k() { 0 } else { AY_128K_ON_48K });
+            ay.push(self.ay_selected);
+            ay.extend_from_slice(&regs);
+            write_block(&mut data, b"AY\0\0", &ay);
+        }
+
+        let mut keyboard = vec![0; 4];
+        keyboard.push(JOYSTICK_NONE);
+        write_block(&mut data, b"KEYB", &keyboard);
+
+        if let Some(tape) = self.tape.as_ref() {
+            write_block(&mut data, b"TAPE", &write_tape(tape)?);
+        }
+
+        Ok(data)
+    }
+
+    fn read_z80r(&mut self, body: &[u8], version: (u8, u8)) -> Result<(), Error> {
+        let body = expect_len(body, 37)?;
+        let word = |i: usize| (body[i] as u16) | ((body[i + 1] as u16) << 8);
+
+        let regs = &mut self.regs;
+        regs.set_all_regs([
+            word(0),
+            word(2),
+            word(4),
+            word(6),
+            word(8),
+            word(10),
+            word(12),
+            word(14),
+            word(16),
+            word(18),
+            word(20),
+            word(22),
+        ]);
+        regs.i = body[24];
+        regs.r = body[25];
+        regs.iff1 = body[26] != 0;
+        regs.iff2 = body[27] != 0;
+        regs.im = body[28] & 0x03;
+        self.tstates = u32::from_le_bytes(body[29..33].try_into().unwrap());
+        self.halted = body[34] & Z80R_HALTED != 0;
+        // MEMPTR took the place of a reserved word in 1.4
+        if version >= (1, 4) {
+            regs.memptr = word(35);
+        }
+        Ok(())
+    }
+
+    fn z80r(&self) -> Vec<u8> {
+        let regs = &self.regs;
+        let mut body = Vec::with_capacity(37);
+        for word in [
+            regs.af(),
+            regs.bc(),
+            regs.de(),
+            regs.hl(),
+            regs.af_aux(),
+            regs.bc_aux(),
+            regs.de_aux(),
+            regs.hl_aux(),
+            regs.ix(),
+            regs.iy(),
+            regs.sp,
+            regs.pc,
+        ] {
+            body.extend_from_slice(&word.to_le_bytes());
+        }
+        body.push(regs.i);
+        body.push(regs.r);
+        body.push(regs.iff1 as u8);
+        body.push(regs.iff2 as u8);
+        body.push(regs.im);
+        body.extend_from_slice(&self.tstates.to_le_bytes());
+        // length of the interrupt signal
+        body.push(if self.is_128k() { 36 } else { 32 });
+        body.push(if self.halted { Z80R_HALTED } else { 0 });
+        body.extend_from_slice(&regs.memptr.to_le_bytes());
+        body
+    }
+
+    fn read_ramp(&mut self, body: &[u8]) -> Result<(), Error> {
+        if body.len() < 3 {
+            return Err(invalid_data("truncated SZX block"));
+        }
+        let flags = (body[0] as u16) | ((body[1] as u16) << 8);
+        let page = body[2] as usize;
+
+        let bank: Bank = if flags & RAMP_COMPRESSED != 0 {
+            inflate(&body[3..])?
+                .try_into()
+                .map_err(|_| invalid_data("invalid SZX page size"))?
+        } else {
+            expect_len(&body[3..], BANK_SIZE)?[..BANK_SIZE]
+                .try_into()
+                .unwrap()
+        };
+
+        let index = if self.is_128k() {
+            (page < 8).then_some(page)
+        } else {
+            match page {
+                5 => Some(0),
+                2 => Some(1),
+                0 => Some(2),
+                _ => None,
+            }
+        };
+        if let Some(index) = index {
+            self.ram[index] = bank;
+        }
+        Ok(())
+    }
+}
+
+fn read_tape(body: &[u8]) -> Result<TapeImage, Error> {
+    let body = expect_len(body, 28)?;
+    let word = |i: usize| (body[i] as u16) | ((body[i + 1] as u16) << 8);
+    let dword = |i: usize| u32::from_le_bytes(body[i..i + 4].try_into().unwrap()) as usize;
+
+    let block = word(0) as usize;
+    let flags = word(2);
+    let len = dword(8);
+    let extension = text(&body[12..28]);
+    let payload = body
+        .get(28..28 + len)
+        .ok_or_else(|| invalid_data("truncated SZX block"))?;
+
+    if flags & TAPE_EMBEDDED == 0 {
+        return Ok(TapeImage {
+            name: text(payload),
+            data: Vec::new(),
+            block,
+        });
+    }
+
+    let data = if flags & TAPE_COMPRESSED != 0 {
+        inflate(payload)?
+    } else {
+        payload.to_vec()
+    };
+    Ok(TapeImage {
+        name: format!("snapshot.{}", extension),
+        data,
+        block,
+    })
+}
+
+fn write_tape(tape: &TapeImage) -> Result<Vec<u8>, Error> {
+    let extension = tape.name.rsplit('.').next().unwrap_or_default();
+
+    let mut body = Vec::new();
+    body.extend_from_slice(&(tape.block as u16).to_le_bytes());
+    if tape.data.is_empty() {
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&(tape.name.len() as u32 + 1).to_le_bytes());
+        let mut ext = extension.as_bytes().to_vec();
+        ext.resize(16, 0);
+        body.extend_from_slice(&ext[..16]);
+        body.extend_from_slice(tape.name.as_bytes());
+        body.push(0);
+    } else {
+        let packed = deflate(&tape.data)?;
+        body.extend_from_slice(&(TAPE_EMBEDDED | TAPE_COMPRESSED).to_le_bytes());
+        body.extend_from_slice(&(tape.data.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packed.len() as u32).to_le_bytes());
+        let mut ext = extension.as_bytes().to_vec();
+        ext.resize(16, 0);
+        body.extend_from_slice(&ext[..16]);
+        body.extend_from_slice(&packed);
+    }
+    Ok(body)
+}
+
+fn write_block(data: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    data.extend_from_slice(id);
+    data.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    data.extend_from_slice(body);
+}
+
+fn deflate(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+    ZlibDecoder::new(data).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+/// Null terminated string.
+fn text(data: &[u8]) -> String {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).to_string()
+}
+
+fn expect_len(body: &[u8], len: usize) -> Result<&[u8], Error> {
+    if body.len() < len {
+        return Err(invalid_data("truncated SZX block"));
+    }
+    Ok(body)
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
@@ -2,54 +2,84 @@ use std::env;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
-use std::sync::mpsc::Sender;
 
 use rfd::FileDialog;
 
-use crate::z80::registers::Registers;
+/// T-states per millisecond
+pub const MS: u32 = 3500;
 
-use super::zx48k::MachineMessage;
-
-struct LoopBlock {
-    id: u8,
-    count: i32,
-    blocks: Vec<Box<dyn Block>>,
+#[derive(Debug, Clone)]
+pub struct DataBlock {
+    pub id: u8,
+    pub flag: u8,
+    pub range: std::ops::Range<usize>,
+    pub pilot: u32,
+    pub pilot_len: u32,
+    pub sync1: u32,
+    pub sync2: u32,
+    pub zero: u32,
+    pub one: u32,
+    pub pause: u32,
+    pub last_byte_len: i8,
 }
 
-struct LoopEndBlock {
-    id: u8,
+/// Symbol of a generalized data block: the pulses (in T-states) and how the level starts.
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub flags: u8,
+    pub pulses: Vec<u32>,
 }
 
 #[derive(Debug, Clone)]
-struct DataBlock {
-    id: u8,
-    flag: u8,
-    range: std::ops::Range<usize>,
-    pilot: u32,
-    pilot_len: u32,
-    sync1: u32,
-    sync2: u32,
-    zero: u32,
-    one: u32,
-    pause: u32,
-    last_byte_len: i8,
+pub struct GeneralizedBlock {
+    pub pause: u32,
+    pub pilot_symbols: Vec<Symbol>,
+    /// (symbol, repetitions)
+    pub pilot_stream: Vec<(u8, u16)>,
+    pub data_symbols: Vec<Symbol>,
+    pub data_len: u32,
+    pub bits_per_symbol: u8,
+    pub range: std::ops::Range<usize>,
 }
 
-struct PulseSeqBlock {
-    id: u8,
-    pulses: Vec<u32>,
+#[derive(Debug, Clone)]
+pub enum Block {
+    /// Standard (0x10), turbo (0x11) and pure data (0x14) blocks, and every .tap block.
+    Data(DataBlock),
+    PureTone {
+        pulse: u32,
+        count: u32,
+    },
+    PulseSeq(Vec<u32>),
+    DirectRecording {
+        ts_per_sample: u32,
+        pause: u32,
+        range: std::ops::Range<usize>,
+        last_byte_len: i8,
+    },
+    Generalized(GeneralizedBlock),
+    /// 0 means "stop the tape".
+    Pause(u32),
+    GroupStart(String),
+    GroupEnd,
+    Jump(i16),
+    LoopStart(u16),
+    LoopEnd,
+    Call(Vec<i16>),
+    Return,
+    StopIf48k,
+    SetLevel(bool),
+    Text(String),
+    ArchiveInfo(Vec<(u8, String)>),
+    /// Blocks with no effect on the signal (hardware type, custom info, glue, ...).
+    Info(u8),
 }
 
-trait Block {}
-
-impl Block for DataBlock {}
-impl Block for PulseSeqBlock {}
-
 #[derive(Debug, Clone)]
 pub struct Tap {
-    blocks: Vec<DataBlock>,
+    pub blocks: Vec<Block>,
     actual_block: usize,
-    data: Vec<u8>,
+    pub data: Vec<u8>,
     pub name: String,
 }
 
@@ -57,7 +87,7 @@ impl Tap {
     pub(crate) async fn load() -> Result<Tap, &'static str> {
         let path: std::path::PathBuf = env::current_dir().unwrap();
         let file = FileDialog::new()
-            .add_filter("tap", &["tap"])
+            .add_filter("tape", &["tap", "tzx"])
             .set_directory(path)
             .pick_file();
 
@@ -80,111 +110,397 @@ impl Tap {
         let mut file = File::open(url)?;
         let mut data = Vec::new();
         file.read_to_end(&mut data)?;
+        Self::from_data(data, String::from(url.to_str().unwrap()))
+    }
 
-        let header = String::from_utf8_lossy(&data[0..7]);
-        if header == "ZXTape!" {
-            unimplemented!();
+    pub fn from_data(data: Vec<u8>, name: String) -> Result<Self, std::io::Error> {
+        let blocks = if data.starts_with(b"ZXTape!") {
+            Self::read_tzx_blocks(&data)?
         } else {
             let mut start = 0;
             let mut blocks = Vec::new();
-            loop {
+            while start + 2 < data.len() {
                 let block = Self::read_default_block(&data, start);
                 start = block.range.end;
-                blocks.push(block);
-                if data.len() <= start {
-                    break;
-                }
+                blocks.push(Block::Data(block));
             }
-            Ok(Self {
-                blocks,
-                actual_block: 0,
-                data,
-                name: String::from(url.to_str().unwrap()),
-            })
-        }
+            blocks
+        };
+
+        Ok(Self {
+            blocks,
+            actual_block: 0,
+            data,
+            name,
+        })
     }
 
+    /// Next data block (flag, data and checksum bytes) for the ROM loader trap.
     pub fn next_block(&mut self) -> Option<Vec<u8>> {
-        if self.actual_block >= self.blocks.len() {
-            return None;
+        while self.actual_block < self.blocks.len() {
+            let block = &self.blocks[self.actual_block];
+            self.actual_block += 1;
+            if let Block::Data(block) = block {
+                return Some(self.data[block.range.clone()].to_vec());
+            }
         }
-        let block = &self.blocks[self.actual_block];
-        self.actual_block += 1;
-        Some(self.data[block.range.clone()].to_vec())
+        None
+    }
+
+    /// Next block read by the ROM traps.
+    pub fn position(&self) -> usize {
+        self.actual_block
+    }
+
+    pub fn rewind(&mut self) {
+        self.actual_block = 0;
+    }
+
+    /// Next block for the ROM loader trap.
+    pub fn seek(&mut self, block: usize) {
+        self.actual_block = block.min(self.blocks.len());
     }
 
     fn read_default_block(data: &[u8], start: usize) -> DataBlock {
         let length = (data[start] as usize) | ((data[start + 1] as usize) << 8);
-        let flag = data[start + 0x02];
+        let range = start + 2..(start + length + 2).min(data.len());
+        Self::standard_block(data, range, 1000)
+    }
+
+    fn standard_block(data: &[u8], range: std::ops::Range<usize>, pause: u32) -> DataBlock {
+        let flag = data.get(range.start).copied().unwrap_or(0);
         let pilot_len = if flag > 128 { 3223 } else { 8063 };
 
         DataBlock {
-            id: data[start],
+            id: 0x10,
             flag,
-            range: start + 2..start + length + 2,
+            range,
             pilot: 2168,
             pilot_len,
             sync1: 667,
             sync2: 735,
             zero: 855,
             one: 1710,
-            pause: 3000,
+            pause: pause * MS,
             last_byte_len: 8,
         }
     }
 
-    // fn read_tzx_block(data: &[u8]) -> (Box<dyn Block>, usize) {
-    //     unimplemented!(); // Implement this method
-    // }
-
-    pub fn load_tap_block(&mut self, regs: Registers, machine_ctl_tx: Sender<MachineMessage>) {
-        let data = self
-            .next_block()
-            .map(|block| block.to_vec())
-            .unwrap_or_else(Vec::new);
-        if data.is_empty() {
-            return;
+    fn read_tzx_blocks(data: &[u8]) -> Result<Vec<Block>, std::io::Error> {
+        let mut blocks = Vec::new();
+        let mut start = 10; // "ZXTape!" 0x1a major minor
+        while start < data.len() {
+            let (block, next) = Self::read_tzx_block(data, start)?;
+            blocks.push(block);
+            start = next;
         }
+        Ok(blocks)
+    }
 
-        let requested_length = regs.de();
-        let start_address = regs.ix();
-        println!("Loading block to {:04x} ({})", start_address, data.len());
-
-        let a = data[0];
-        println!("{} == {} : {}", regs.a_alt, a, regs.a_alt == a);
-        println!("requestedLength: {}", requested_length);
-        if regs.a_alt == a {
-            if regs.f_alt.c {
-                let mut checksum = data[0];
-                for i in 0..(requested_length as usize) {
-                    let loaded_byte = data[i + 1];
-                    // self.mem_write(start_address.wrapping_add(i as u16), loaded_byte);
-                    checksum ^= loaded_byte;
+    fn read_tzx_block(data: &[u8], start: usize) -> Result<(Block, usize), std::io::Error> {
+        let mut r = Reader { data, pos: start };
+        let id = r.u8()?;
+        let block = match id {
+            0x10 => {
+                let pause = r.u16()? as u32;
+                let len = r.u16()? as usize;
+                let range = r.range(len)?;
+                Block::Data(Self::standard_block(data, range, pause))
+            }
+            0x11 => {
+                let pilot = r.u16()? as u32;
+                let sync1 = r.u16()? as u32;
+                let sync2 = r.u16()? as u32;
+                let zero = r.u16()? as u32;
+                let one = r.u16()? as u32;
+                let pilot_len = r.u16()? as u32;
+                let last_byte_len = r.last_byte_len()?;
+                let pause = r.u16()? as u32 * MS;
+                let len = r.u24()? as usize;
+                let range = r.range(len)?;
+                Block::Data(DataBlock {
+                    id,
+                    flag: data.get(range.start).copied().unwrap_or(0),
+                    range,
+                    pilot,
+                    pilot_len,
+                    sync1,
+                    sync2,
+                    zero,
+                    one,
+                    pause,
+                    last_byte_len,
+                })
+            }
+            0x12 => Block::PureTone {
+                pulse: r.u16()? as u32,
+                count: r.u16()? as u32,
+            },
+            0x13 => {
+                let count = r.u8()? as usize;
+                let mut pulses = Vec::with_capacity(count);
+                for _ in 0..count {
+                    pulses.push(r.u16()? as u32);
+                }
+                Block::PulseSeq(pulses)
+            }
+            0x14 => {
+                let zero = r.u16()? as u32;
+                let one = r.u16()? as u32;
+                let last_byte_len = r.last_byte_len()?;
+                let pause = r.u16()? as u32 * MS;
+                let len = r.u24()? as usize;
+                let range = r.range(len)?;
+                Block::Data(DataBlock {
+                    id,
+                    flag: data.get(range.start).copied().unwrap_or(0),
+                    range,
+                    pilot: 0,
+                    pilot_len: 0,
+                    sync1: 0,
+                    sync2: 0,
+                    zero,
+                    one,
+                    pause,
+                    last_byte_len,
+                })
+            }
+            0x15 => {
+                let ts_per_sample = r.u16()? as u32;
+                let pause = r.u16()? as u32 * MS;
+                let last_byte_len = r.last_byte_len()?;
+                let len = r.u24()? as usize;
+                Block::DirectRecording {
+                    ts_per_sample,
+                    pause,
+                    range: r.range(len)?,
+                    last_byte_len,
                 }
+            }
+            0x19 => {
+                let len = r.u32()? as usize;
+                let end = r.pos + len;
+                let block = Self::read_generalized_block(&mut r)?;
+                r.pos = end;
+                Block::Generalized(block)
+            }
+            0x20 => Block::Pause(r.u16()? as u32 * MS),
+            0x21 => {
+                let len = r.u8()? as usize;
+                Block::GroupStart(r.text(len)?)
+            }
+            0x22 => Block::GroupEnd,
+            0x23 => Block::Jump(r.u16()? as i16),
+            0x24 => Block::LoopStart(r.u16()?),
+            0x25 => Block::LoopEnd,
+            0x26 => {
+                let count = r.u16()? as usize;
+                let mut calls = Vec::with_capacity(count);
+                for _ in 0..count {
+                    calls.push(r.u16()? as i16);
+                }
+                Block::Call(calls)
+            }
+            0x27 => Block::Return,
+            0x28 => {
+                let len = r.u16()? as usize;
+                r.range(len)?;
+                Block::Info(id)
+            }
+            0x2a => {
+                let len = r.u32()? as usize;
+                r.range(len)?;
+                Block::StopIf48k
+            }
+            0x2b => {
+                let len = r.u32()? as usize;
+                let level = r.u8()? != 0;
+                r.range(len.saturating_sub(1))?;
+                Block::SetLevel(level)
+            }
+            0x30 => {
+                let len = r.u8()? as usize;
+                Block::Text(r.text(len)?)
+            }
+            0x31 => {
+                let _time = r.u8()?;
+                let len = r.u8()? as usize;
+                Block::Text(r.text(len)?)
+            }
+            0x32 => {
+                let len = r.u16()? as usize;
+                let end = r.pos + len;
+                let count = r.u8()?;
+                let mut info = Vec::new();
+                for _ in 0..count {
+                    let id = r.u8()?;
+                    let len = r.u8()? as usize;
+                    info.push((id, r.text(len)?));
+                }
+                r.pos = end;
+                Block::ArchiveInfo(info)
+            }
+            0x33 => {
+                let count = r.u8()? as usize;
+                r.range(count * 3)?;
+                Block::Info(id)
+            }
+            0x34 => {
+                r.range(8)?;
+                Block::Info(id)
+            }
+            0x35 => {
+                r.range(16)?;
+                let len = r.u32()? as usize;
+                r.range(len)?;
+                Block::Info(id)
+            }
+            0x40 => {
+                r.u8()?;
+                let len = r.u24()? as usize;
+                r.range(len)?;
+                Block::Info(id)
+            }
+            0x5a => {
+                r.range(9)?;
+                Block::Info(id)
+            }
+            _ => {
+                // every other block (0x16, 0x17, 0x18, future extensions) starts with its length
+                let len = r.u32()? as usize;
+                r.range(len)?;
+                Block::Info(id)
+            }
+        };
+        if r.pos > data.len() {
+            return Err(truncated());
+        }
+        Ok((block, r.pos))
+    }
 
-                if start_address == 0x4000 {}
+    fn read_generalized_block(r: &mut Reader) -> Result<GeneralizedBlock, std::io::Error> {
+        let pause = r.u16()? as u32 * MS;
+        let totp = r.u32()?;
+        let npp = r.u8()? as usize;
+        let asp = match r.u8()? {
+            0 => 256,
+            n => n as usize,
+        };
+        let totd = r.u32()?;
+        let npd = r.u8()? as usize;
+        let asd = match r.u8()? {
+            0 => 256,
+            n => n as usize,
+        };
 
-                println!(
-                    "{} == {} : {}",
-                    checksum,
-                    data[requested_length as usize + 1],
-                    checksum == data[requested_length as usize + 1]
-                );
-                // regs.f.c = true;
-            } else {
-                // regs.f.c = true;
+        let mut pilot_symbols = Vec::new();
+        let mut pilot_stream = Vec::new();
+        if totp > 0 {
+            pilot_symbols = Self::read_symbols(r, asp, npp)?;
+            for _ in 0..totp {
+                let symbol = r.u8()?;
+                let repeat = r.u16()?;
+                pilot_stream.push((symbol, repeat));
             }
-            println!("done");
-        } else {
-            // regs.f.c = false;
-            println!("BAD Block");
         }
 
-        // regs.pc = 0x05e2;
-        machine_ctl_tx
-            .send(MachineMessage::CPUSetRegisters(regs))
-            .unwrap();
-        machine_ctl_tx.send(MachineMessage::CPUResume).unwrap();
-        println!("Done\n--------");
+        let mut data_symbols = Vec::new();
+        let mut bits_per_symbol = 0;
+        let mut range = r.pos..r.pos;
+        if totd > 0 {
+            data_symbols = Self::read_symbols(r, asd, npd)?;
+            while (1 << bits_per_symbol) < asd {
+                bits_per_symbol += 1;
+            }
+            let bits_per_symbol = bits_per_symbol.max(1);
+            let len = (totd as usize * bits_per_symbol + 7) / 8;
+            range = r.range(len)?;
+        }
+
+        Ok(GeneralizedBlock {
+            pause,
+            pilot_symbols,
+            pilot_stream,
+            data_symbols,
+            data_len: totd,
+            bits_per_symbol: (bits_per_symbol as u8).max(1),
+            range,
+        })
+    }
+
+    fn read_symbols(
+        r: &mut Reader,
+        count: usize,
+        max_pulses: usize,
+    ) -> Result<Vec<Symbol>, std::io::Error> {
+        let mut symbols = Vec::with_capacity(count);
+        for _ in 0..count {
+            let flags = r.u8()?;
+            let mut pulses = Vec::with_capacity(max_pulses);
+            for _ in 0..max_pulses {
+                pulses.push(r.u16()? as u32);
+            }
+            // a 0 length pulse ends the symbol
+            if let Some(end) = pulses.iter().position(|p| *p == 0) {
+                pulses.truncate(end);
+            }
+            symbols.push(Symbol { flags, pulses });
+        }
+        Ok(symbols)
+    }
+}
+
+/// Little endian cursor over the tape file, failing on truncated blocks.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Result<u8, std::io::Error> {
+        let v = *self.data.get(self.pos).ok_or_else(truncated)?;
+        self.pos += 1;
+        Ok(v)
+    }
+
+    fn u16(&mut self) -> Result<u16, std::io::Error> {
+        Ok(self.u8()? as u16 | (self.u8()? as u16) << 8)
+    }
+
+    fn u24(&mut self) -> Result<u32, std::io::Error> {
+        Ok(self.u16()? as u32 | (self.u8()? as u32) << 16)
+    }
+
+    fn u32(&mut self) -> Result<u32, std::io::Error> {
+        Ok(self.u16()? as u32 | (self.u16()? as u32) << 16)
+    }
+
+    /// Bits used in the last byte of a data block, 1 to 8.
+    fn last_byte_len(&mut self) -> Result<i8, std::io::Error> {
+        match self.u8()? {
+            bits @ 1..=8 => Ok(bits as i8),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "bad bit count in the last byte of a tape block",
+            )),
+        }
+    }
+
+    fn range(&mut self, len: usize) -> Result<std::ops::Range<usize>, std::io::Error> {
+        let range = self.pos..self.pos + len;
+        if range.end > self.data.len() {
+            return Err(truncated());
+        }
+        self.pos = range.end;
+        Ok(range)
     }
+
+    fn text(&mut self, len: usize) -> Result<String, std::io::Error> {
+        let range = self.range(len)?;
+        Ok(String::from_utf8_lossy(&self.data[range]).to_string())
+    }
+}
+
+fn truncated() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "truncated tape block")
 }
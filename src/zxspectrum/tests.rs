@@ -0,0 +1,940 @@
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+
+use flate2::read::ZlibDecoder;
+
+use crate::signals::SignalReq;
+use crate::z80::bus::{Access, Bus};
+use crate::z80::cpu::CPU;
+use crate::z80::registers::Registers;
+
+use super::ay::AY;
+use super::deck::TapeDeck;
+use super::headless::{KeyScript, Options};
+use super::rewind::{History, Keyframe};
+use super::screenshot::{crc32, to_png, to_rgb, ShotOptions};
+use super::snapshot::{Hardware, Snapshot, TapeImage};
+use super::tap::{Block, Tap};
+use super::ula::{
+    key, FrameSink, KeyChange, NoFrames, SCREEN_HEIGHT, SCREEN_WIDTH, SRC_SIZE, TIMINGS_128K,
+    TIMINGS_48K, ULA,
+};
+
+#[test]
+fn test_tzx_blocks() {
+    let mut tzx = b"ZXTape!\x1a\x01\x14".to_vec();
+    // standard speed: 1000ms pause, 3 bytes
+    tzx.extend_from_slice(&[0x10, 0xe8, 0x03, 0x03, 0x00, 0xff, 0xaa, 0x55]);
+    // pure tone: 2168 x 100
+    tzx.extend_from_slice(&[0x12, 0x78, 0x08, 0x64, 0x00]);
+    // pulse sequence: 667, 735
+    tzx.extend_from_slice(&[0x13, 0x02, 0x9b, 0x02, 0xdf, 0x02]);
+    // loop x2 / pause 500ms / loop end
+    tzx.extend_from_slice(&[0x24, 0x02, 0x00, 0x20, 0xf4, 0x01, 0x25]);
+    // group start "abc" / group end
+    tzx.extend_from_slice(&[0x21, 0x03, b'a', b'b', b'c', 0x22]);
+    // generalized data: no pilot, 2 symbols of 2 pulses, 8 symbols of data
+    tzx.extend_from_slice(&[0x19, 0x19, 0x00, 0x00, 0x00]);
+    tzx.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    tzx.extend_from_slice(&[0x08, 0x00, 0x00, 0x00, 0x02, 0x02]);
+    tzx.extend_from_slice(&[0x00, 0x57, 0x03, 0x57, 0x03, 0x00, 0xae, 0x06, 0xae, 0x06]);
+    tzx.push(0xa5);
+    // unknown extension block, skipped by its length
+    tzx.extend_from_slice(&[0x4b, 0x02, 0x00, 0x00, 0x00, 0x01, 0x02]);
+    // stop the tape if in 48K mode
+    tzx.extend_from_slice(&[0x2a, 0x00, 0x00, 0x00, 0x00]);
+
+    let mut tap = Tap::from_data(tzx, "test.tzx".to_string()).unwrap();
+    assert_eq!(tap.blocks.len(), 11);
+
+    match &tap.blocks[0] {
+        Block::Data(data) => {
+            assert_eq!(data.flag, 0xff);
+            assert_eq!(data.pilot_len, 3223);
+            assert_eq!(data.pause, 1000 * 3500);
+        }
+        b => panic!("unexpected block {:?}", b),
+    }
+    assert!(matches!(
+        tap.blocks[1],
+        Block::PureTone {
+            pulse: 2168,
+            count: 100
+        }
+    ));
+    assert!(matches!(&tap.blocks[2], Block::PulseSeq(p) if p == &vec![667, 735]));
+    assert!(matches!(tap.blocks[3], Block::LoopStart(2)));
+    assert!(matches!(tap.blocks[4], Block::Pause(1_750_000)));
+    assert!(matches!(&tap.blocks[6], Block::GroupStart(name) if name == "abc"));
+    match &tap.blocks[8] {
+        Block::Generalized(block) => {
+            assert!(block.pilot_stream.is_empty());
+            assert_eq!(block.data_symbols.len(), 2);
+            assert_eq!(block.data_symbols[1].pulses, vec![1710, 1710]);
+            assert_eq!(block.data_len, 8);
+            assert_eq!(block.bits_per_symbol, 1);
+            assert_eq!(tap.data[block.range.clone()], [0xa5]);
+        }
+        b => panic!("unexpected block {:?}", b),
+    }
+    assert!(matches!(tap.blocks[9], Block::Info(0x4b)));
+    assert!(matches!(tap.blocks[10], Block::StopIf48k));
+
+    assert_eq!(tap.next_block(), Some(vec![0xff, 0xaa, 0x55]));
+    assert_eq!(tap.next_block(), None);
+}
+
+#[test]
+fn test_tzx_truncated() {
+    let tzx = b"ZXTape!\x1a\x01\x14\x10\xe8\x03\x10\x00\xff".to_vec();
+    assert!(Tap::from_data(tzx, "test.tzx".to_string()).is_err());
+
+    // turbo data and direct recording blocks, the last byte uses 1 to 8 bits
+    let turbo = |bits: u8| {
+        let mut tzx = b"ZXTape!\x1a\x01\x14\x11".to_vec();
+        tzx.extend_from_slice(&[0x78, 0x08, 0x9b, 0x02, 0xdf, 0x02, 0x57, 0x03, 0xae, 0x06]);
+        tzx.extend_from_slice(&[0x7f, 0x1f, bits, 0x00, 0x00, 0x01, 0x00, 0x00, 0xff]);
+        Tap::from_data(tzx, "test.tzx".to_string())
+    };
+    let direct = |bits: u8| {
+        let mut tzx = b"ZXTape!\x1a\x01\x14\x15".to_vec();
+        tzx.extend_from_slice(&[0x4f, 0x00, 0x00, 0x00, bits, 0x01, 0x00, 0x00, 0xff]);
+        Tap::from_data(tzx, "test.tzx".to_string())
+    };
+    for bits in [1, 8] {
+        assert!(turbo(bits).is_ok());
+        assert!(direct(bits).is_ok());
+    }
+    for bits in [0, 9, 0x80, 0xff] {
+        assert!(turbo(bits).is_err());
+        assert!(direct(bits).is_err());
+    }
+}
+
+#[test]
+fn test_deck_pulses() {
+    let mut tzx = b"ZXTape!\x1a\x01\x14".to_vec();
+    // pure tone: 10 x 3
+    tzx.extend_from_slice(&[0x12, 0x0a, 0x00, 0x03, 0x00]);
+    // group start "abc" / group end
+    tzx.extend_from_slice(&[0x21, 0x03, b'a', b'b', b'c', 0x22]);
+    // pulse sequence: 5, 7
+    tzx.extend_from_slice(&[0x13, 0x02, 0x05, 0x00, 0x07, 0x00]);
+    // stop the tape if in 48K mode
+    tzx.extend_from_slice(&[0x2a, 0x00, 0x00, 0x00, 0x00]);
+    // pure tone: 10 x 1
+    tzx.extend_from_slice(&[0x12, 0x0a, 0x00, 0x01, 0x00]);
+
+    let mut deck = TapeDeck::new(true);
+    deck.insert(Tap::from_data(tzx, "test.tzx".to_string()).unwrap());
+    assert!(!deck.tick());
+
+    deck.play();
+    let mut levels = Vec::new();
+    while deck.is_playing() {
+        levels.push(deck.tick());
+    }
+
+    let mut expected = Vec::new();
+    for (len, level) in [(10, true), (10, false), (10, true), (5, false), (7, true)] {
+        expected.extend(std::iter::repeat_n(level, len));
+    }
+    // the tick that finds the stop block keeps the last level
+    expected.push(true);
+    assert_eq!(levels, expected);
+
+    // the last block plays after the stop
+    deck.play();
+    assert!(!deck.tick());
+    assert_eq!(deck.next_edge(), 9);
+
+    deck.rewind();
+    assert!(!deck.is_playing());
+    assert!(!deck.level());
+}
+
+#[test]
+fn test_deck_jump_loop() {
+    // jump to itself, then +1 / -1 going round, and no pulses in sight
+    for jumps in [
+        &[0x23, 0x00, 0x00][..],
+        &[0x23, 0x01, 0x00, 0x23, 0xff, 0xff],
+    ] {
+        let mut tzx = b"ZXTape!\x1a\x01\x14".to_vec();
+        tzx.extend_from_slice(jumps);
+
+        let mut deck = TapeDeck::new(true);
+        deck.insert(Tap::from_data(tzx, "test.tzx".to_string()).unwrap());
+        deck.play();
+        assert!(!deck.tick());
+        assert!(!deck.is_playing());
+    }
+}
+
+#[test]
+fn test_deck_last_byte_bits() {
+    // pure data: 1 T-state pulses, no pause, 1 byte; direct recording: 1 T-state samples
+    let mut tzx = b"ZXTape!\x1a\x01\x14".to_vec();
+    tzx.extend_from_slice(&[
+        0x14, 0x01, 0x00, 0x01, 0x00, 0x08, 0x00, 0x00, 0x01, 0x00, 0x00,
+    ]);
+    tzx.push(0xff);
+    tzx.extend_from_slice(&[0x15, 0x01, 0x00, 0x00, 0x00, 0x08, 0x01, 0x00, 0x00, 0xff]);
+    let tap = Tap::from_data(tzx, "test.tzx".to_string()).unwrap();
+    let ticks = |tap: Tap| {
+        let mut deck = TapeDeck::new(true);
+        deck.insert(tap);
+        deck.play();
+        let mut ticks = 0;
+        while deck.is_playing() {
+            deck.tick();
+            ticks += 1;
+        }
+        ticks
+    };
+
+    // bit counts a tape file would be rejected for play all 8 bits
+    let mut bad = tap.clone();
+    for block in bad.blocks.iter_mut() {
+        match block {
+            Block::Data(block) => block.last_byte_len = 100,
+            Block::DirectRecording { last_byte_len, .. } => *last_byte_len = -1,
+            _ => (),
+        }
+    }
+    assert_eq!(ticks(bad), ticks(tap));
+}
+
+#[test]
+fn test_deck_fast_load() {
+    let mut tzx = b"ZXTape!\x1a\x01\x14".to_vec();
+    // pure tone: 2168 x 1000
+    tzx.extend_from_slice(&[0x12, 0x78, 0x08, 0xe8, 0x03]);
+
+    let mut deck = TapeDeck::new(true);
+    deck.insert(Tap::from_data(tzx, "test.tzx".to_string()).unwrap());
+    deck.fast_load = true;
+    deck.play();
+
+    // LD-SAMPLE: IN A,(FE) at 0x05ED, polled in a loop
+    for _ in 0..8 {
+        deck.on_instruction(0x05ed);
+        deck.on_ear_read();
+        deck.on_instruction(0x05ef);
+    }
+    assert!(deck.is_fast_forwarding());
+
+    deck.fast_load = false;
+    assert!(!deck.is_fast_forwarding());
+    deck.fast_load = true;
+
+    // back to BASIC, no more EAR reads
+    for pc in 0..=2000 {
+        deck.on_instruction(pc);
+    }
+    assert!(!deck.is_fast_forwarding());
+}
+
+fn test_registers() -> Registers {
+    let mut regs = Registers::new();
+    regs.set_all_regs([
+        0x1234, 0x2345, 0x3456, 0x4567, 0x5678, 0x6789, 0x789a, 0x89ab, 0x9abc, 0xabcd, 0x8000,
+        0x6000,
+    ]);
+    regs.i = 0x3f;
+    regs.r = 0x12;
+    regs.im = 1;
+    regs.iff1 = true;
+    regs.iff2 = true;
+    regs
+}
+
+#[test]
+fn test_deck_state() {
+    use crate::state::{SaveState, StateReader, StateWriter};
+
+    let mut tzx = b"ZXTape!\x1a\x01\x14".to_vec();
+    // loop x2 / pulse sequence: 5, 7 / loop end
+    tzx.extend_from_slice(&[0x24, 0x02, 0x00, 0x13, 0x02, 0x05, 0x00, 0x07, 0x00, 0x25]);
+    // standard speed: 1ms pause, 2 bytes
+    tzx.extend_from_slice(&[0x10, 0x01, 0x00, 0x02, 0x00, 0xff, 0xaa]);
+
+    let mut deck = TapeDeck::new(true);
+    deck.insert(Tap::from_data(tzx, "test.tzx".to_string()).unwrap());
+    deck.play();
+    for _ in 0..20 {
+        deck.tick();
+    }
+
+    let mut w = StateWriter::new();
+    deck.save_state(&mut w);
+    let state = w.into_inner();
+    let mut restored = TapeDeck::new(true);
+    restored.load_state(&mut StateReader::new(&state)).unwrap();
+    assert!(restored.is_playing());
+
+    while deck.is_playing() {
+        assert_eq!(deck.tick(), restored.tick());
+        assert_eq!(deck.next_edge(), restored.next_edge());
+    }
+    assert!(!restored.is_playing());
+
+    // a call from a block the tape does not have
+    let mut bad = state.clone();
+    let at = bad.len() - 22;
+    bad[at..at + 5].copy_from_slice(&[1, 0xff, 0xff, 0x00, 0x00]);
+    let mut restored = TapeDeck::new(true);
+    assert!(restored.load_state(&mut StateReader::new(&bad)).is_err());
+}
+
+#[test]
+fn test_bad_state() {
+    use crate::state::{SaveState, StateReader, StateWriter};
+
+    fn saved(state: &impl SaveState) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        state.save_state(&mut w);
+        w.into_inner()
+    }
+    let ula = || {
+        let (_, keys) = mpsc::channel();
+        ULA::new(Box::new(NoFrames), keys, None, TIMINGS_48K)
+    };
+
+    // the pixels queued at the end, 8 of them at the start of the frame
+    let state = saved(&ula());
+    assert!(ula().load_state(&mut StateReader::new(&state)).is_ok());
+    let mut bad = state[..state.len() - 34].to_vec();
+    bad.extend_from_slice(&[0, 0]);
+    assert!(ula().load_state(&mut StateReader::new(&bad)).is_err());
+
+    // the envelope step before the attack and hold flags
+    let state = saved(&AY::new());
+    assert!(AY::new().load_state(&mut StateReader::new(&state)).is_ok());
+    let mut bad = state.clone();
+    let at = bad.len() - 3;
+    bad[at] = 16;
+    assert!(AY::new().load_state(&mut StateReader::new(&bad)).is_err());
+}
+
+#[test]
+fn test_rewind_history() {
+    let mut history = History::new(100);
+    let interval = 25 * 100;
+    let mut states = Vec::new();
+    for n in 0..5u64 {
+        let mut state = vec![0u8; 1000];
+        state[n as usize * 10] = n as u8 + 1;
+        state[999] = n as u8;
+        if n == 3 {
+            // a different size is kept whole
+            state.push(0xff);
+        }
+        let keyframe = Keyframe {
+            clock: n * interval,
+            instructions: n * 1000,
+            keyboard: [n as u8; 8],
+        };
+        assert!(history.is_due(keyframe.clock));
+        history.push(keyframe, state.clone());
+        history.record_input(n * interval + 10, [0x01; 8]);
+        states.push(state);
+    }
+    assert!(!history.is_due(4 * interval + 1));
+    assert_eq!(history.seconds(2), 100 * 100);
+
+    assert_eq!(history.before_clock(2 * interval + 1), Some(2));
+    assert_eq!(history.before_instruction(3999), Some(3));
+    // further back than the history goes
+    assert_eq!(history.before_clock(0), Some(0));
+
+    let (keyframe, state, inputs) = history.restore(2);
+    assert_eq!(keyframe.instructions, 2000);
+    assert_eq!(keyframe.keyboard, [2; 8]);
+    assert_eq!(state, states[2]);
+    assert_eq!(inputs.len(), 3);
+    assert_eq!(inputs[0].0, 2 * interval + 10);
+    assert_eq!(history.len(), 3);
+
+    history.truncate(2 * interval + 11);
+    let (_, state, inputs) = history.restore(0);
+    assert_eq!(state, states[0]);
+    assert_eq!(inputs.len(), 3);
+}
+
+#[test]
+fn test_sna_48k() {
+    let mut snapshot = Snapshot::new(Hardware::Zx48k, test_registers());
+    snapshot.border = 2;
+    snapshot.write(0x4000, 0xaa);
+    snapshot.write(0xffff, 0x55);
+
+    let data = snapshot.to_sna();
+    assert_eq!(data.len(), 49179);
+    // PC is pushed on the stack
+    assert_eq!(data[23..25], [0xfe, 0x7f]);
+    assert_eq!(data[27 + 0x3ffe..27 + 0x4000], [0x00, 0x60]);
+
+    let loaded = Snapshot::from_sna(&data).unwrap();
+    assert!(!loaded.is_128k());
+    assert_eq!(loaded.regs.dump_registers(), snapshot.regs.dump_registers());
+    assert_eq!(loaded.regs.i, 0x3f);
+    assert_eq!(loaded.regs.r, 0x12);
+    assert_eq!(loaded.regs.im, 1);
+    assert!(loaded.regs.iff1 && loaded.regs.iff2);
+    assert_eq!(loaded.border, 2);
+    assert_eq!(loaded.read(0x4000), 0xaa);
+    assert_eq!(loaded.read(0xffff), 0x55);
+
+    assert!(Snapshot::from_sna(&data[1..]).is_err());
+}
+
+#[test]
+fn test_sna_128k() {
+    let mut snapshot = Snapshot::new(Hardware::Zx128k, test_registers());
+    snapshot.border = 5;
+    snapshot.port_7ffd = 0x13;
+    for page in 0..8 {
+        snapshot.ram[page][0] = page as u8;
+    }
+
+    let data = snapshot.to_sna();
+    assert_eq!(data.len(), 131103);
+    // page 3 is the one at 0xC000
+    assert_eq!(data[27 + 0x8000], 3);
+
+    let loaded = Snapshot::from_sna(&data).unwrap();
+    assert!(loaded.is_128k());
+    assert_eq!(loaded.regs.dump_registers(), snapshot.regs.dump_registers());
+    assert_eq!(loaded.port_7ffd, 0x13);
+    for page in 0..8 {
+        assert_eq!(loaded.ram[page][0], page as u8);
+    }
+
+    // with page 5 at 0xC000 the six other pages are saved
+    snapshot.port_7ffd = 0x05;
+    let long = snapshot.to_sna();
+    assert_eq!(long.len(), 147487);
+    assert_eq!(Snapshot::from_sna(&long).unwrap().ram[6][0], 6);
+
+    // 0x7FFD paging a page that does not fit the length of the file
+    let mut short = data.clone();
+    short[49179 + 2] = 0x05;
+    let e = Snapshot::from_sna(&short).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    let mut long = long;
+    long[49179 + 2] = 0x13;
+    assert!(Snapshot::from_sna(&long).is_err());
+}
+
+#[test]
+fn test_z80_round_trip() {
+    let mut snapshot = Snapshot::new(Hardware::Zx128k, test_registers());
+    snapshot.regs.r = 0x92;
+    snapshot.border = 6;
+    snapshot.port_7ffd = 0x17;
+    snapshot.ay_regs = Some([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]);
+    snapshot.ay_selected = 7;
+    snapshot.tstates = 12345;
+    for page in 0..8 {
+        snapshot.ram[page][0] = page as u8;
+        // runs of 0xED and a single 0xED followed by a run
+        snapshot.ram[page][100..103].copy_from_slice(&[0xed, 0xed, 0xed]);
+        snapshot.ram[page][200..208].copy_from_slice(&[0xed, 0, 0, 0, 0, 0, 0, 0xed]);
+        snapshot.ram[page][0x3fff] = 0xed;
+    }
+
+    let data = snapshot.to_z80();
+    assert!(data.len() < 8 * 400);
+    let loaded = Snapshot::from_z80(&data).unwrap();
+    assert_eq!(loaded.hardware, Hardware::Zx128k);
+    assert_eq!(loaded.regs.dump_registers(), snapshot.regs.dump_registers());
+    assert_eq!(loaded.regs.r, 0x92);
+    assert_eq!(loaded.regs.im, 1);
+    assert_eq!(loaded.border, 6);
+    assert_eq!(loaded.port_7ffd, 0x17);
+    assert_eq!(loaded.ay_regs, snapshot.ay_regs);
+    assert_eq!(loaded.ay_selected, 7);
+    assert_eq!(loaded.tstates, 12345);
+    assert_eq!(loaded.ram, snapshot.ram);
+
+    let mut snapshot = Snapshot::new(Hardware::Zx48k, test_registers());
+    snapshot.write(0x4000, 0x12);
+    snapshot.write(0x8000, 0x34);
+    snapshot.write(0xc000, 0x56);
+    let loaded = Snapshot::from_z80(&snapshot.to_z80()).unwrap();
+    assert_eq!(loaded.hardware, Hardware::Zx48k);
+    assert_eq!(loaded.ay_regs, None);
+    assert_eq!(loaded.ram, snapshot.ram);
+}
+
+#[test]
+fn test_z80_v1() {
+    let mut z80 = vec![0; 30];
+    z80[6] = 0x34; // PC
+    z80[7] = 0x12;
+    z80[12] = 0x20 | (3 << 1); // compressed, border 3
+    z80[29] = 0x02; // IM 2
+                    // 0x4000 bytes of 0x00 and 0x8000 of 0xff, in runs of 255 plus one byte
+    for (count, byte) in [(64, 0x00), (128, 0xff)] {
+        for _ in 0..count {
+            z80.extend_from_slice(&[0xed, 0xed, 0xff, byte, byte]);
+        }
+    }
+    // end marker
+    z80.extend_from_slice(&[0x00, 0xed, 0xed, 0x00]);
+
+    let loaded = Snapshot::from_z80(&z80).unwrap();
+    assert_eq!(loaded.hardware, Hardware::Zx48k);
+    assert_eq!(loaded.regs.pc, 0x1234);
+    assert_eq!(loaded.regs.im, 2);
+    assert_eq!(loaded.border, 3);
+    assert_eq!(loaded.read(0x7fff), 0x00);
+    assert_eq!(loaded.read(0x8000), 0xff);
+    assert_eq!(loaded.read(0xffff), 0xff);
+
+    assert!(Snapshot::from_z80(&z80[..500]).is_err());
+}
+
+#[test]
+fn test_szx_round_trip() {
+    let mut snapshot = Snapshot::new(Hardware::Zx48k, test_registers());
+    snapshot.regs.memptr = 0x5eed;
+    snapshot.border = 4;
+    snapshot.ay_regs = Some([0x10; 16]);
+    snapshot.ay_selected = 14;
+    snapshot.tstates = 30000;
+    snapshot.halted = true;
+    snapshot.tape = Some(TapeImage {
+        name: "game.tap".to_string(),
+        data: vec![0x13, 0x00, 0x00, 0x03],
+        block: 1,
+    });
+    snapshot.write(0x4000, 0x11);
+    snapshot.write(0x8000, 0x22);
+    snapshot.write(0xc000, 0x33);
+
+    let data = snapshot.to_szx().unwrap();
+    assert_eq!(data[0..8], *b"ZXST\x01\x04\x01\x00");
+
+    let loaded = Snapshot::from_szx(&data).unwrap();
+    assert_eq!(loaded.hardware, Hardware::Zx48k);
+    assert_eq!(loaded.regs.dump_registers(), snapshot.regs.dump_registers());
+    assert_eq!(loaded.regs.memptr, 0x5eed);
+    assert_eq!(loaded.regs.i, 0x3f);
+    assert!(loaded.regs.iff1);
+    assert_eq!(loaded.border, 4);
+    assert_eq!(loaded.ay_regs, Some([0x10; 16]));
+    assert_eq!(loaded.ay_selected, 14);
+    assert_eq!(loaded.tstates, 30000);
+    assert!(loaded.halted);
+    assert_eq!(loaded.ram, snapshot.ram);
+    let tape = loaded.tape.unwrap();
+    assert_eq!(tape.block, 1);
+    assert_eq!(tape.data, vec![0x13, 0x00, 0x00, 0x03]);
+    assert_eq!(tape.name, "snapshot.tap");
+
+    let mut snapshot = Snapshot::new(Hardware::Zx128k, test_registers());
+    snapshot.port_7ffd = 0x1f;
+    for page in 0..8 {
+        snapshot.ram[page][0x1234] = page as u8 + 1;
+    }
+    let loaded = Snapshot::from_szx(&snapshot.to_szx().unwrap()).unwrap();
+    assert_eq!(loaded.hardware, Hardware::Zx128k);
+    assert_eq!(loaded.port_7ffd, 0x1f);
+    assert_eq!(loaded.ram, snapshot.ram);
+    assert!(loaded.tape.is_none());
+
+    assert!(Snapshot::from_szx(b"ZXST\x01\x04\x01\x00Z80R\x25\x00\x00\x00").is_err());
+}
+
+#[test]
+fn test_contention_pattern() {
+    let t = TIMINGS_48K;
+    assert_eq!(t.contention(14334), 0);
+    let line: Vec<u8> = (14335..14343).map(|ts| t.contention(ts)).collect();
+    assert_eq!(line, [6, 5, 4, 3, 2, 1, 0, 0]);
+    assert_eq!(t.contention(14335 + 120), 6);
+    assert_eq!(t.contention(14335 + 128), 0);
+    assert_eq!(t.contention(14335 + 224), 6);
+    assert_eq!(t.contention(14335 + 191 * 224 + 1), 5);
+    assert_eq!(t.contention(14335 + 192 * 224), 0);
+}
+
+/// 48K memory and ULA, with the contention of the real machine.
+struct ContendedBus {
+    ula: ULA,
+    mem: Vec<u8>,
+}
+
+impl Bus for ContendedBus {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.mem[addr as usize]
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.mem[addr as usize] = data;
+    }
+
+    fn io_read(&mut self, port: u16) -> u8 {
+        self.ula.read_port(port)
+    }
+
+    fn io_write(&mut self, port: u16, data: u8) {
+        self.ula.write_port(port, data);
+    }
+
+    fn tick(&mut self) {
+        for _ in 0..2 {
+            self.ula.tick();
+            if let SignalReq::Read = self.ula.signals.mem {
+                self.ula.signals.data = self.mem[self.ula.signals.addr as usize];
+            }
+        }
+    }
+
+    fn wait(&mut self, access: Option<Access>) -> bool {
+        match access {
+            Some(access @ Access::Mem { addr, .. }) => {
+                self.ula.holds_cpu(access, addr & 0xc000 == 0x4000)
+            }
+            Some(access @ Access::Io { port, .. }) => {
+                self.ula.holds_cpu(access, port & 0xc000 == 0x4000)
+            }
+            None => false,
+        }
+    }
+}
+
+/// Runs the instruction `code` from 0x8000, its opcode fetch starting at T-state `start` of
+/// the frame; returns its T-states and A.
+fn run_at(code: &[u8], a: u8, start: u32) -> (usize, u8) {
+    let mut bus = bus_at(code, start);
+    let mut cpu = CPU::new();
+    cpu.regs.pc = 0x8000;
+    cpu.regs.a = a;
+
+    let mut ts = 1;
+    while cpu.tick_with(&mut bus).is_none() {
+        ts += 1;
+    }
+    (ts, cpu.regs.a)
+}
+
+/// Runs the instruction in `code` from `pc`, with HL pointing at `hl`; returns its T-states.
+fn run_from(code: &[u8], pc: u16, hl: u16, start: u32) -> usize {
+    let mut bus = bus_at(&[], start);
+    bus.mem[pc as usize..pc as usize + code.len()].copy_from_slice(code);
+    let mut cpu = CPU::new();
+    cpu.regs.pc = pc;
+    cpu.regs.set_rr(2, hl);
+
+    let mut ts = 1;
+    while cpu.tick_with(&mut bus).is_none() {
+        ts += 1;
+    }
+    ts
+}
+
+/// A bus with `code` at 0x8000, the CPU runs its first T-state at T-state `start` of the
+/// frame.
+fn bus_at(code: &[u8], start: u32) -> ContendedBus {
+    let (_, key_rx) = mpsc::channel();
+    let mut ula = ULA::new(Box::new(NoFrames), key_rx, None, TIMINGS_48K);
+    // no sound, and the bus ticks the ULA before the first T-state
+    ula.set_turbo(true);
+    ula.set_ts_in_frame(start - 1);
+
+    let mut mem = vec![0; 0x10000];
+    // the first two cells of the screen
+    mem[0x4000..0x4002].copy_from_slice(&[0x11, 0x22]);
+    mem[0x5800..0x5802].copy_from_slice(&[0x33, 0x44]);
+    mem[0x8000..0x8000 + code.len()].copy_from_slice(code);
+    ContendedBus { ula, mem }
+}
+
+#[test]
+fn test_contention_cycles() {
+    // LD A,(0x4000): the read starts 10 T-states in
+    let ld = [0x3a, 0x00, 0x40];
+    assert_eq!(run_at(&ld, 0, 14325).0, 13 + 6);
+    assert_eq!(run_at(&ld, 0, 14326).0, 13 + 5);
+    assert_eq!(run_at(&ld, 0, 14331).0, 13);
+    assert_eq!(run_at(&ld, 0, 14325 + 128).0, 13);
+    // uncontended memory is not held during the screen
+    assert_eq!(run_at(&[0x00], 0, 14335).0, 4);
+    // OUT (0xfe),A, ULA port in uncontended memory: N:1, C:3
+    assert_eq!(run_at(&[0xd3, 0xfe], 0x00, 14327).0, 11 + 6);
+    assert_eq!(run_at(&[0xd3, 0xfe], 0x00, 14328).0, 11 + 5);
+    // IN A,(0xff) from 0x40ff, not the ULA in contended memory: C:1, C:1, C:1, C:1
+    assert_eq!(run_at(&[0xdb, 0xff], 0x40, 14328).0, 11 + 6 + 6);
+    // IN A,(0xfe) from 0x40fe, ULA in contended memory: C:1, C:3
+    assert_eq!(run_at(&[0xdb, 0xfe], 0x40, 14328).0, 11 + 6);
+    // OUT (0xff),A to 0x00ff, nothing contended
+    assert_eq!(run_at(&[0xd3, 0xff], 0x00, 14328).0, 11);
+    // JR 0 from 0x6000: pc:4, pc+1:3, pc+1:1 x5, every internal T-state held on its own
+    assert_eq!(
+        run_from(&[0x18, 0x00], 0x6000, 0, 14335),
+        12 + 6 + 4 + 5 + 6 + 6
+    );
+    assert_eq!(run_from(&[0x18, 0x00], 0x6000, 0, 14335 + 128), 12);
+    // INC (HL) from 0x6000 on 0x4000: pc:4, hl:3, hl:1, hl:3
+    assert_eq!(run_from(&[0x34], 0x6000, 0x4000, 14335), 11 + 6 + 4 + 5);
+    assert_eq!(run_from(&[0x34], 0x6000, 0x4000, 14335 + 128), 11);
+}
+
+#[test]
+fn test_floating_bus() {
+    // IN A,(0xff) samples the bus 8 T-states in
+    let read = |start| run_at(&[0xdb, 0xff], 0x00, start).1;
+    let bytes: Vec<u8> = (14330..14338).map(read).collect();
+    assert_eq!(bytes, [0x11, 0x33, 0x22, 0x44, 0xff, 0xff, 0xff, 0xff]);
+    // border and the right side of the line
+    assert_eq!(read(14329), 0xff);
+    assert_eq!(read(14330 + 128), 0xff);
+    // same cells, next pixel line
+    assert_eq!(read(14330 + 224), 0x00);
+    assert_eq!(read(14331 + 224), 0x33);
+}
+
+#[test]
+fn test_border_timing() {
+    // LD A,2 / OUT (0xfe),A / LD A,5 / OUT (0xfe),A / HALT, from T-state 40 + phase of line 40:
+    // the ports are written 15 and 33 T-states in
+    let code = [0x3e, 0x02, 0xd3, 0xfe, 0x3e, 0x05, 0xd3, 0xfe, 0x76];
+    let (black, red, cyan) = (0x000000ff, 0xc04010ff, 0x50c0b0ff);
+    // the writes land at every phase of the 8 T-state ULA cycle
+    for phase in 0..8 {
+        let mut bus = bus_at(&code, 40 * 224 + 40 + phase);
+        let mut cpu = CPU::new();
+        cpu.regs.pc = 0x8000;
+        for _ in 0..1000 {
+            cpu.tick_with(&mut bus);
+        }
+
+        let bitmap = bus.ula.pixels();
+        // line 40 is drawn at y 24, T-state t of a line at x 48 + 2 * t
+        let line = |y: usize| -> Vec<u32> {
+            (0..SCREEN_WIDTH)
+                .map(|x| {
+                    let i = (x + y * SCREEN_WIDTH) * 4;
+                    u32::from_be_bytes(bitmap[i..i + 4].try_into().unwrap())
+                })
+                .collect()
+        };
+        let x = |t: u32| 48 + 2 * (t + phase) as usize;
+        let y24 = line(24);
+        assert!(
+            y24[x(40)..x(55)].iter().all(|p| *p == black),
+            "phase {phase}"
+        );
+        assert!(y24[x(55)..x(73)].iter().all(|p| *p == red), "phase {phase}");
+        assert!(y24[x(73)..].iter().all(|p| *p == cyan), "phase {phase}");
+        assert!(line(25).iter().all(|p| *p == cyan), "phase {phase}");
+    }
+}
+
+/// Keeps every frame it is given.
+struct Frames(Arc<Mutex<Vec<Vec<u8>>>>);
+
+impl FrameSink for Frames {
+    fn frame(&mut self, pixels: &[u8]) {
+        self.0.lock().unwrap().push(pixels.to_vec());
+    }
+}
+
+#[test]
+fn test_frame_sink() {
+    let frames = Arc::new(Mutex::new(Vec::new()));
+    let (_, key_rx) = mpsc::channel();
+    let mut ula = ULA::new(Box::new(Frames(frames.clone())), key_rx, None, TIMINGS_48K);
+    ula.write_port(0xfe, 0x02);
+
+    // two pixels per T-state
+    for _ in 0..2 * TIMINGS_48K.frame_ts() {
+        ula.tick();
+    }
+    let frames = frames.lock().unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].len(), SRC_SIZE * 4);
+    assert_eq!(frames[0], ula.pixels());
+    // the top left corner is border
+    assert_eq!(frames[0][0..4], 0xc04010ffu32.to_be_bytes());
+
+    // the next frame is under way past its top left corner, the last one is kept whole
+    ula.write_port(0xfe, 0x05);
+    for _ in 0..TIMINGS_48K.width * 280 {
+        ula.tick();
+    }
+    assert_eq!(ula.pixels()[0..4], 0x50c0b0ffu32.to_be_bytes());
+    assert_eq!(ula.last_frame(), &frames[0][..]);
+}
+
+#[test]
+fn test_headless_options() {
+    let args = "--headless --128k game.tap --frames 0x20 --until-pc $8000 --keys @3 \
+                --dump-mem m.bin --screenshot s.png --no-border --scale 2";
+    let options = Options::parse(args.split_whitespace().map(String::from)).unwrap();
+    assert!(options.is_128k);
+    assert_eq!(options.file, Some(PathBuf::from("game.tap")));
+    assert_eq!(options.frames, 32);
+    assert_eq!(options.until_pc, Some(0x8000));
+    assert_eq!(options.dump_mem, Some(PathBuf::from("m.bin")));
+    assert_eq!(options.screenshot, Some(PathBuf::from("s.png")));
+    assert_eq!(
+        options.shot,
+        ShotOptions {
+            border: false,
+            scale: 2
+        }
+    );
+
+    let defaults = Options::parse(Vec::new()).unwrap();
+    assert_eq!(defaults.frames, 500);
+    assert_eq!(defaults.until_pc, None);
+    assert_eq!(defaults.shot, ShotOptions::default());
+
+    for args in [
+        "--frames",
+        "--frames ten",
+        "--until-pc 0x10000",
+        "--keys shift",
+        "--scale 3",
+        "--fast",
+        "a.sna b.sna",
+    ] {
+        assert!(
+            Options::parse(args.split(' ').map(String::from)).is_err(),
+            "{}",
+            args
+        );
+    }
+}
+
+#[test]
+fn test_key_script() {
+    // LOAD "" on the 48K
+    let script = KeyScript::parse("@100 j sym+p sym+p  ENTER").unwrap();
+    let mut j = [0; 8];
+    j[6] = 0b01000;
+    let mut quote = [0; 8];
+    quote[5] = 0b00001;
+    quote[7] = 0b00010;
+    let mut enter = [0; 8];
+    enter[6] = 0b00001;
+
+    assert_eq!(script.at(0), None);
+    assert_eq!(script.at(100), Some(j));
+    assert_eq!(script.at(105), Some([0; 8]));
+    assert_eq!(script.at(110), Some(quote));
+    assert_eq!(script.at(115), Some([0; 8]));
+    assert_eq!(script.at(120), Some(quote));
+    assert_eq!(script.at(130), Some(enter));
+    assert_eq!(script.at(135), Some([0; 8]));
+    assert_eq!(script.at(140), None);
+}
+
+#[test]
+fn test_live_keys() {
+    let (keys_tx, keys) = mpsc::channel();
+    let mut ula = ULA::new(Box::new(NoFrames), keys, None, TIMINGS_48K);
+    let (row, bit) = key("J").unwrap();
+    assert_eq!((row, bit), (6, 3));
+
+    let pressed = true;
+    keys_tx.send(KeyChange { row, bit, pressed }).unwrap();
+    ula.tick();
+    assert_eq!(ula.keyboard()[6], 0b01000);
+    assert!(ula.take_keys_changed());
+    // the row reads the key low
+    assert_eq!(ula.read_port(0xbffe) & 0x1f, 0b10111);
+
+    // the script has the keyboard to itself
+    ula.set_live_input(false);
+    let pressed = false;
+    keys_tx.send(KeyChange { row, bit, pressed }).unwrap();
+    ula.tick();
+    assert_eq!(ula.keyboard()[6], 0b01000);
+    ula.set_live_input(true);
+    ula.tick();
+    assert_eq!(ula.keyboard()[6], 0);
+}
+
+#[test]
+fn test_screenshot() {
+    let (border, paper) = ([0xc0, 0x40, 0x10, 0xff], [0x50, 0xc0, 0xb0, 0xff]);
+    let mut pixels = border.repeat(SRC_SIZE);
+    // the top left pixel of the paper
+    let i = (48 + 48 * SCREEN_WIDTH) * 4;
+    pixels[i..i + 4].copy_from_slice(&paper);
+
+    let (width, height, rgb) = to_rgb(&pixels, ShotOptions::default());
+    assert_eq!((width, height), (SCREEN_WIDTH, SCREEN_HEIGHT));
+    assert_eq!(rgb[..3], border[..3]);
+
+    let options = ShotOptions {
+        border: false,
+        scale: 2,
+    };
+    let (width, height, rgb) = to_rgb(&pixels, options);
+    assert_eq!((width, height), (512, 384));
+    assert_eq!(rgb.len(), 512 * 384 * 3);
+    for offset in [0, 3, 512 * 3, 512 * 3 + 3] {
+        assert_eq!(rgb[offset..offset + 3], paper[..3]);
+    }
+    assert_eq!(rgb[6..9], border[..3]);
+
+    assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    let png = to_png(&pixels, options).unwrap();
+    assert_eq!(png[..8], *b"\x89PNG\r\n\x1a\n");
+    assert_eq!(png[12..16], *b"IHDR");
+    assert_eq!(png[16..24], [0, 0, 2, 0, 0, 0, 1, 128]);
+    assert_eq!(png[png.len() - 12..], *b"\0\0\0\0IEND\xae\x42\x60\x82");
+
+    let len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+    assert_eq!(png[37..41], *b"IDAT");
+    let mut raw = Vec::new();
+    ZlibDecoder::new(&png[41..41 + len])
+        .read_to_end(&mut raw)
+        .unwrap();
+    let rows: Vec<u8> = rgb
+        .chunks_exact(512 * 3)
+        .flat_map(|row| [0].iter().chain(row).copied())
+        .collect();
+    assert_eq!(raw, rows);
+}
+
+#[test]
+fn test_paper_128k() {
+    // the first cell of the first 8 lines is all ink, blue on white
+    let mut mem = vec![0; 0x10000];
+    for line in 0..8 {
+        mem[0x4000 + (line << 8)] = 0xff;
+    }
+    mem[0x5800..0x5802].copy_from_slice(&[0x39, 0x39]);
+
+    for timings in [TIMINGS_48K, TIMINGS_128K] {
+        let (_, key_rx) = mpsc::channel();
+        let mut ula = ULA::new(Box::new(NoFrames), key_rx, None, timings);
+        ula.set_turbo(true);
+        for _ in 0..2 * timings.frame_ts() {
+            ula.tick();
+            if let SignalReq::Read = ula.signals.mem {
+                ula.signals.data = mem[ula.signals.addr as usize];
+            }
+        }
+
+        let pixels = ula.pixels();
+        let pixel = |x: usize, y: usize| {
+            let i = (x + y * SCREEN_WIDTH) * 4;
+            u32::from_be_bytes(pixels[i..i + 4].try_into().unwrap())
+        };
+        for y in 48..56 {
+            for x in 48..56 {
+                assert_eq!(pixel(x, y), 0x2030c0ff, "{} {}", x, y);
+            }
+            assert_eq!(pixel(56, y), 0xc0c0c0ff, "{}", y);
+        }
+    }
+}
@@ -1,19 +1,58 @@
-use iced::keyboard::{key::Named, Event as KeyEvent, Key};
-
 use crate::signals::{SignalReq, Signals};
-use iced::futures::channel::mpsc::{Receiver, Sender};
-use std::sync::{mpsc, Arc, Mutex};
-
-use super::zx48k::UICommands;
+use crate::state::{invalid_state, SaveState, StateReader, StateWriter};
+use crate::z80::bus::Access;
+use std::io::Error;
+use std::sync::mpsc;
 
 pub const SRC_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT + 1;
 
-const WIDTH: usize = 448;
-const HEIGHT: usize = 312;
+/// Frame geometry of a ULA model, in pixel clocks (two per T-state) and lines.
+#[derive(Debug, Clone, Copy)]
+pub struct Timings {
+    pub width: usize,
+    pub height: usize,
+    pub top_border: usize,
+    pub int_len: usize,
+    /// First T-state of the frame where the CPU is held by the ULA.
+    pub contention_start: usize,
+}
+
+pub const TIMINGS_48K: Timings = Timings {
+    width: 448,
+    height: 312,
+    top_border: 64,
+    int_len: 64,
+    contention_start: 14335,
+};
+
+pub const TIMINGS_128K: Timings = Timings {
+    width: 456,
+    height: 311,
+    top_border: 63,
+    int_len: 72,
+    contention_start: 14361,
+};
+
+impl Timings {
+    pub fn frame_ts(&self) -> usize {
+        (self.width / 2) * self.height
+    }
+
+    /// T-states the CPU is held when it accesses contended memory at T-state `ts` of the
+    /// frame: 6,5,4,3,2,1,0,0 over the 128 T-states of each screen line.
+    pub fn contention(&self, ts: usize) -> u8 {
+        const PATTERN: [u8; 8] = [6, 5, 4, 3, 2, 1, 0, 0];
+        let line = self.width / 2;
+        match ts.checked_sub(self.contention_start) {
+            Some(t) if t < line * 192 && t % line < 128 => PATTERN[t % 8],
+            _ => 0,
+        }
+    }
+}
 
 pub const SCREEN_WIDTH: usize = 256 + (SCREEN_BORDER * 2);
 pub const SCREEN_HEIGHT: usize = 192 + (SCREEN_BORDER * 2);
-const SCREEN_BORDER: usize = 48;
+pub const SCREEN_BORDER: usize = 48;
 
 #[derive(Debug)]
 pub struct SomeError; // No fields.
@@ -25,30 +64,79 @@ const PALETTE: [u32; 16] = [
 
 pub struct ULA {
     keyboard_row: [u8; 8],
-    border_colour: u32,
+    /// Key events are read from the UI, off while the machine replays recorded input
+    live_input: bool,
+    keys_changed: bool,
+    border: u8,
     frame: u8,
     col: usize,
     row: usize,
-    floating_bus: u8,
     ear: bool,
-    ear_active: bool,
     buzzer: u8,
-    sound: mpsc::Sender<f32>,
+    /// Buzzer samples for the audio output, none when nobody plays them.
+    sound: Option<mpsc::Sender<f32>>,
     sound_frame: u8,
+    aux_sound: f32,
+    turbo: bool,
     screen_data: u8,
     attr_data: u8,
     screen_data_2: u8,
     attr_data_2: u8,
-    pub content: bool,
-    ts: usize,
+    timings: Timings,
 
     pub signals: Signals,
 
     data: Vec<u32>,
-    bitmaps: [Arc<Mutex<Vec<u8>>>; 2],
-    buffer: usize,
-    event_rx: Receiver<KeyEvent>,
-    ui_ctl_tx: Sender<UICommands>,
+    /// The frame being drawn, RGBA
+    pixels: Vec<u8>,
+    /// The last frame completed, what screenshots show.
+    last_frame: Vec<u8>,
+    frames: Box<dyn FrameSink>,
+    keys: mpsc::Receiver<KeyChange>,
+}
+
+/// Receives every frame the ULA completes.
+pub trait FrameSink: Send {
+    /// `pixels` are RGBA, `SCREEN_WIDTH` x `SCREEN_HEIGHT`.
+    fn frame(&mut self, pixels: &[u8]);
+}
+
+/// Drops the frames, for runs that read `ULA::last_frame` when they need to.
+pub struct NoFrames;
+
+impl FrameSink for NoFrames {
+    fn frame(&mut self, _pixels: &[u8]) {}
+}
+
+/// A key of the matrix going down or up, as sent by the frontend.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyChange {
+    /// Half-row and bit of the key, as `key` finds them.
+    pub row: usize,
+    pub bit: usize,
+    pub pressed: bool,
+}
+
+/// Keyboard half-rows, in the order of the ULA rows, bit 0 first.
+const KEYS: [[&str; 5]; 8] = [
+    ["caps", "z", "x", "c", "v"],
+    ["a", "s", "d", "f", "g"],
+    ["q", "w", "e", "r", "t"],
+    ["1", "2", "3", "4", "5"],
+    ["0", "9", "8", "7", "6"],
+    ["p", "o", "i", "u", "y"],
+    ["enter", "l", "k", "j", "h"],
+    ["space", "sym", "m", "n", "b"],
+];
+
+/// (row, bit) of a key by its name: the letter or digit on it, or caps, sym, enter and space.
+pub fn key(name: &str) -> Option<(usize, usize)> {
+    let name = name.to_lowercase();
+    KEYS.iter().enumerate().find_map(|(row, keys)| {
+        keys.iter()
+            .position(|key| *key == name)
+            .map(|bit| (row, bit))
+    })
 }
 
 pub enum ULASignal {
@@ -57,39 +145,40 @@ pub enum ULASignal {
 
 impl ULA {
     pub fn new(
-        bitmaps: [Arc<Mutex<Vec<u8>>>; 2],
-        event_rx: Receiver<KeyEvent>,
-        ui_ctl_tx: Sender<UICommands>,
-        sound_tx: mpsc::Sender<f32>,
+        frames: Box<dyn FrameSink>,
+        keys: mpsc::Receiver<KeyChange>,
+        sound_tx: Option<mpsc::Sender<f32>>,
+        timings: Timings,
     ) -> Self {
         ULA {
             // listener: None,
             // cpu,
             keyboard_row: [0; 8],
-            border_colour: 0,
+            border: 0,
             frame: 0,
             col: 0,
             row: 0,
-            floating_bus: 0,
             ear: false,
-            ear_active: false,
             buzzer: 0,
             sound: sound_tx,
             sound_frame: 0,
+            aux_sound: 0.0,
+            turbo: false,
+            live_input: true,
+            keys_changed: false,
             screen_data: 0,
             attr_data: 0,
             screen_data_2: 0,
             attr_data_2: 0,
-            content: false,
-            ts: 0,
+            timings,
 
             signals: Signals::default(),
 
-            bitmaps,
             data: vec![0; 8],
-            buffer: 0,
-            event_rx,
-            ui_ctl_tx,
+            pixels: vec![0; SRC_SIZE * 4],
+            last_frame: vec![0; SRC_SIZE * 4],
+            frames,
+            keys,
         }
     }
 
@@ -113,19 +202,23 @@ impl ULA {
         self.sound_frame += 1;
         if self.sound_frame == 200 {
             self.sound_frame = 0;
-            let t = -0.05 + ((self.buzzer as f32) * 0.1);
-            match self.sound.send(t) {
-                Ok(_) => (),
-                Err(e) => println!("send error: {}", e),
+            let t = -0.05
+                + ((self.buzzer as f32) * 0.1)
+                + ((self.ear as u8 as f32) * 0.02)
+                + (self.aux_sound * 0.15);
+            if let Some(sound) = self.sound.as_ref().filter(|_| !self.turbo) {
+                match sound.send(t) {
+                    Ok(_) => (),
+                    Err(e) => println!("send error: {}", e),
+                }
             }
         }
 
-        let in_screen = (0..256).contains(&self.col) && (0..192).contains(&self.row);
-        self.content = in_screen;
+        let in_screen = Self::in_screen(self.col, self.row);
 
         if in_screen {
-            self.floating_bus = self.signals.data;
-            match self.ts % 16 {
+            // the fetch phase follows the column, a 128K line is not a multiple of 16 long
+            match self.col % 16 {
                 0 => {
                     self.signals.addr = self.get_screen_addr();
                     self.signals.mem = SignalReq::Read
@@ -169,69 +262,68 @@ impl ULA {
                     self.data.append(colors.to_vec().as_mut());
                 }
 
-                9 | 10 | 11 => {}
-
-                12 | 13 | 14 | 15 => {
-                    self.content = false;
-                }
-                _ => panic!("{} {}", self.ts, self.ts % 16),
+                9..=15 => {}
+                _ => unreachable!(),
             }
         } else {
-            if self.ts % 16 == 6 || self.ts % 16 == 14 {
-                // for i in 0..8 {
-                // self.bitmap[self.col + i + (self.row * WIDTH)] = self.border_colour;
-                self.data.append([self.border_colour; 8].to_vec().as_mut());
-                // }
-            }
+            // the border colour is sampled on every pixel, two per T-state
+            self.data.push(PALETTE[self.border as usize]);
         }
 
-        self.ts += 1;
-
         let d = self.data.remove(0).to_be_bytes();
         if let Ok((x, y)) = self.get_xy(self.col, self.row) {
-            let mut bm = self.bitmaps[self.buffer].lock().unwrap();
             let idx = (x + (y * SCREEN_WIDTH)) * 4;
-            bm[idx + 0] = d[0];
-            bm[idx + 1] = d[1];
-            bm[idx + 2] = d[2];
-            bm[idx + 3] = d[3];
-            drop(bm);
+            self.pixels[idx..idx + 4].copy_from_slice(&d);
         }
 
         self.col += 1;
-        if self.col == WIDTH {
+        if self.col == self.timings.width {
             self.col = 0;
             self.row += 1;
-            if self.row == HEIGHT {
+            if self.row == self.timings.height {
                 self.row = 0;
                 self.frame_done();
-                self.ts = 0;
             }
         }
 
-        if self.row == (HEIGHT - 64) && self.col < 64 {
+        if self.row == (self.timings.height - self.timings.top_border)
+            && self.col < self.timings.int_len
+        {
             self.signals.interrupt = true;
         } else {
             self.signals.interrupt = false;
         }
 
-        match self.event_rx.try_next() {
-            Ok(Some(e)) => self.on_key(e),
-            _ => (),
-            // Ok(Some(event)) => self.on_key(event),
-            // Err(_) => (),
+        if !self.live_input {
+            return;
+        }
+        if let Ok(key) = self.keys.try_recv() {
+            self.on_key(key);
+        }
+    }
+
+    fn in_screen(col: usize, row: usize) -> bool {
+        col < 256 && row < 192
+    }
+
+    /// Pixels waiting to be drawn when `col` starts, the paper is fetched ahead of drawing it.
+    fn queued(col: usize, row: usize) -> usize {
+        match col % 16 {
+            phase @ 0..=8 if Self::in_screen(col, row) => 8 - phase,
+            phase if Self::in_screen(col, row) => 24 - phase,
+            _ => 8,
         }
     }
 
     fn get_xy(&self, col: usize, row: usize) -> Result<(usize, usize), SomeError> {
         let mut x = col + SCREEN_BORDER - 8;
         let mut y = row + SCREEN_BORDER;
-        if x >= WIDTH {
-            x -= WIDTH;
+        if x >= self.timings.width {
+            x -= self.timings.width;
             y += 1;
         }
-        if y >= HEIGHT {
-            y -= HEIGHT;
+        if y >= self.timings.height {
+            y -= self.timings.height;
         }
 
         if (x < SCREEN_WIDTH) && (y < SCREEN_HEIGHT) {
@@ -241,10 +333,23 @@ impl ULA {
     }
 
     fn frame_done(&mut self) {
-        self.ui_ctl_tx
-            .start_send(UICommands::DrawBuffer(self.buffer))
-            .unwrap();
-        self.buffer = 1 - self.buffer;
+        self.frame = self.frame.wrapping_add(1);
+        self.last_frame.copy_from_slice(&self.pixels);
+        // when running flat out only some frames reach the UI
+        if self.turbo && self.frame % 8 != 0 {
+            return;
+        }
+        self.frames.frame(&self.pixels);
+    }
+
+    /// The frame being drawn, RGBA, `SCREEN_WIDTH` x `SCREEN_HEIGHT`.
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    /// The last whole frame, same layout as `pixels`.
+    pub fn last_frame(&self) -> &[u8] {
+        &self.last_frame
     }
 
     pub fn read_port(&self, port: u16) -> u8 {
@@ -260,21 +365,131 @@ impl ULA {
                     // );
                 }
             }
-            if self.ear_active && self.ear {
+            if self.ear {
                 data |= 0b11100000;
             } else {
                 data |= 0b10100000;
             }
             return data;
         }
-        self.floating_bus
+        self.floating_bus()
+    }
+
+    /// Value on the data bus when nothing drives it, as read by the CPU IO cycle under way:
+    /// the bitmap, attribute, bitmap and attribute bytes of two cells the ULA fetches in the
+    /// first 4 T-states of every 8 of a screen line, 0xff in the rest and in the border.
+    pub fn floating_bus(&self) -> u8 {
+        let t = &self.timings;
+        let line = t.width / 2;
+        // the bus is sampled on the T-state before the port read is served
+        let ts = (self.ts_in_frame() as usize + t.frame_ts() - 1) % t.frame_ts();
+        match ts.checked_sub(t.contention_start + 3) {
+            Some(ts) if ts < line * 192 && ts % line < 128 => match ts % 8 {
+                0 => self.screen_data,
+                1 => self.attr_data,
+                2 => self.screen_data_2,
+                3 => self.attr_data_2,
+                _ => 0xff,
+            },
+            _ => 0xff,
+        }
+    }
+
+    /// Whether the ULA holds the CPU before `access`, `contended` tells if its address (the
+    /// high byte of a port) is in contended memory. Memory cycles are held on their first
+    /// T-state; IO cycles on the ones of the C:1,C:3 (ULA port) or C:1,C:1,C:1,C:1 patterns,
+    /// on the second only for a ULA port in uncontended memory.
+    pub fn holds_cpu(&self, access: Access, contended: bool) -> bool {
+        let held = match access {
+            Access::Mem { t, .. } => contended && t == 1,
+            Access::Io { port, t } => match t {
+                1 => contended,
+                2 => contended || port & 0x0001 == 0,
+                _ => contended && port & 0x0001 != 0,
+            },
+        };
+        held && self.timings.contention(self.ts_in_frame() as usize) > 0
+    }
+
+    /// T-states since the interrupt at the start of the frame.
+    pub fn ts_in_frame(&self) -> u32 {
+        let t = &self.timings;
+        let row = (self.row + t.top_border) % t.height;
+        ((row * t.width + self.col) / 2) as u32
+    }
+
+    pub fn set_ts_in_frame(&mut self, ts: u32) {
+        let t = &self.timings;
+        let pos = (ts as usize * 2) % (t.width * t.height);
+        self.row = (pos / t.width + t.height - t.top_border) % t.height;
+        self.col = pos % t.width;
+        let border = PALETTE[self.border as usize];
+        self.data.resize(Self::queued(self.col, self.row), border);
+    }
+
+    pub fn border(&self) -> u8 {
+        self.border
+    }
+
+    pub fn set_border(&mut self, colour: u8) {
+        self.border = colour & 0x07;
+    }
+
+    /// EAR input level, driven by the tape deck.
+    pub fn set_ear(&mut self, level: bool) {
+        self.ear = level;
+    }
+
+    /// Running faster than real time: no sound and fewer frames.
+    pub fn set_turbo(&mut self, turbo: bool) {
+        self.turbo = turbo;
+    }
+
+    pub fn set_live_input(&mut self, live: bool) {
+        self.live_input = live;
+    }
+
+    /// A key event changed the keyboard since the last call.
+    pub fn take_keys_changed(&mut self) -> bool {
+        std::mem::take(&mut self.keys_changed)
+    }
+
+    pub fn keyboard(&self) -> [u8; 8] {
+        self.keyboard_row
+    }
+
+    pub fn set_keyboard(&mut self, rows: [u8; 8]) {
+        self.keyboard_row = rows;
+    }
+
+    /// Level (0.0 to 1.0) of an external sound source mixed with the buzzer, like the AY.
+    pub fn mix(&mut self, level: f32) {
+        self.aux_sound = level;
     }
 
     pub fn write_port(&mut self, port: u16, data: u8) {
         if port & 0xff == 0xfe {
-            self.border_colour = PALETTE[data as usize & 0x07];
+            self.border = data & 0x07;
             self.buzzer = (data & 16) >> 4;
-            self.ear_active = (data & 24) != 0;
+            self.repaint_border();
+        }
+    }
+
+    /// The CPU writes the port after the ULA has sampled the border for the T-state of the
+    /// write: its two pixels, the last queued, take the new colour.
+    fn repaint_border(&mut self) {
+        let (col, row) = match (self.col, self.row) {
+            (0, 0) => (self.timings.width - 1, self.timings.height - 1),
+            (0, row) => (self.timings.width - 1, row - 1),
+            (col, row) => (col - 1, row),
+        };
+        if Self::in_screen(col, row) {
+            return;
+        }
+        let colour = PALETTE[self.border as usize];
+        let len = self.data.len();
+        for pixel in &mut self.data[len.saturating_sub(2)..] {
+            *pixel = colour;
         }
     }
 
@@ -309,96 +524,13 @@ impl ULA {
         colors
     }
 
-    fn on_key(&mut self, event: KeyEvent) {
-        // println!("event: {:?}", event);
-        let (key, pressed) = match event {
-            KeyEvent::KeyPressed { key, .. } => (key, true),
-            KeyEvent::KeyReleased { key, .. } => (key, false),
-            KeyEvent::ModifiersChanged(_) => return,
-        };
-
-        match key.as_ref() {
-            // Key::Unidentified => todo!(),
-            Key::Character("1") => self.set_bit(3, 1, pressed),
-            Key::Character("2") => self.set_bit(3, 2, pressed),
-            Key::Character("3") => self.set_bit(3, 3, pressed),
-            Key::Character("4") => self.set_bit(3, 4, pressed),
-            Key::Character("5") => self.set_bit(3, 5, pressed),
-
-            Key::Character("0") => self.set_bit(4, 1, pressed),
-            Key::Character("9") => self.set_bit(4, 2, pressed),
-            Key::Character("8") => self.set_bit(4, 3, pressed),
-            Key::Character("7") => self.set_bit(4, 4, pressed),
-            Key::Character("6") => self.set_bit(4, 5, pressed),
-
-            Key::Character("q") => self.set_bit(2, 1, pressed),
-            Key::Character("w") => self.set_bit(2, 2, pressed),
-            Key::Character("e") => self.set_bit(2, 3, pressed),
-            Key::Character("r") => self.set_bit(2, 4, pressed),
-            Key::Character("t") => self.set_bit(2, 5, pressed),
-
-            Key::Character("p") => self.set_bit(5, 1, pressed),
-            Key::Character("o") => self.set_bit(5, 2, pressed),
-            Key::Character("i") => self.set_bit(5, 3, pressed),
-            Key::Character("u") => self.set_bit(5, 4, pressed),
-            Key::Character("y") => self.set_bit(5, 5, pressed),
-
-            Key::Character("a") => self.set_bit(1, 1, pressed),
-            Key::Character("s") => self.set_bit(1, 2, pressed),
-            Key::Character("d") => self.set_bit(1, 3, pressed),
-            Key::Character("f") => self.set_bit(1, 4, pressed),
-            Key::Character("g") => self.set_bit(1, 5, pressed),
-
-            Key::Named(Named::Enter) => self.set_bit(6, 1, pressed),
-            Key::Character("l") => self.set_bit(6, 2, pressed),
-            Key::Character("k") => self.set_bit(6, 3, pressed),
-            Key::Character("j") => self.set_bit(6, 4, pressed),
-            Key::Character("h") => self.set_bit(6, 5, pressed),
-
-            Key::Named(Named::Shift) => self.set_bit(0, 1, pressed),
-            Key::Character("z") => self.set_bit(0, 2, pressed),
-            Key::Character("x") => self.set_bit(0, 3, pressed),
-            Key::Character("c") => self.set_bit(0, 4, pressed),
-            Key::Character("v") => self.set_bit(0, 5, pressed),
-
-            Key::Named(Named::Space) => self.set_bit(7, 1, pressed),
-            Key::Named(Named::Alt) => self.set_bit(7, 2, pressed),
-            Key::Character("m") => self.set_bit(7, 3, pressed),
-            Key::Character("n") => self.set_bit(7, 4, pressed),
-            Key::Character("b") => self.set_bit(7, 5, pressed),
-
-            Key::Named(Named::ArrowUp) => {
-                self.set_bit(0, 1, pressed);
-                self.set_bit(4, 4, pressed);
-            }
-            Key::Named(Named::ArrowDown) => {
-                self.set_bit(0, 1, pressed);
-                self.set_bit(4, 5, pressed);
-            }
-            Key::Named(Named::ArrowLeft) => {
-                self.set_bit(0, 1, pressed);
-                self.set_bit(3, 5, pressed);
-            }
-            Key::Named(Named::ArrowRight) => {
-                self.set_bit(0, 1, pressed);
-                self.set_bit(4, 3, pressed);
-            }
-            Key::Named(Named::Backspace) => {
-                self.set_bit(0, 1, pressed);
-                self.set_bit(4, 1, pressed);
-            }
-
-            Key::Character(c) => println!("Unknown key: {}", c),
-            _ => (),
-        }
-    }
-
-    fn set_bit(&mut self, row: usize, bit: usize, set: bool) {
-        let b = 1 << (bit - 1);
-        if set {
-            self.keyboard_row[row] |= b;
+    fn on_key(&mut self, key: KeyChange) {
+        self.keys_changed = true;
+        let b = 1 << key.bit;
+        if key.pressed {
+            self.keyboard_row[key.row] |= b;
         } else {
-            self.keyboard_row[row] &= !b;
+            self.keyboard_row[key.row] &= !b;
         }
     }
 
@@ -406,3 +538,57 @@ impl ULA {
         self.keyboard_row = [0; 8];
     }
 }
+
+/// The keyboard is live input and is not part of the state.
+impl SaveState for ULA {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.border);
+        w.u8(self.frame);
+        w.u16(self.col as u16);
+        w.u16(self.row as u16);
+        w.bool(self.ear);
+        w.u8(self.buzzer);
+        w.u8(self.sound_frame);
+        w.f32(self.aux_sound);
+        w.u8(self.screen_data);
+        w.u8(self.attr_data);
+        w.u8(self.screen_data_2);
+        w.u8(self.attr_data_2);
+        self.signals.save_state(w);
+        w.u16(self.data.len() as u16);
+        for pixel in &self.data {
+            w.u32(*pixel);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.border = r.u8()?;
+        self.frame = r.u8()?;
+        self.col = r.u16()? as usize;
+        self.row = r.u16()? as usize;
+        self.ear = r.bool()?;
+        self.buzzer = r.u8()?;
+        self.sound_frame = r.u8()?;
+        self.aux_sound = r.f32()?;
+        self.screen_data = r.u8()?;
+        self.attr_data = r.u8()?;
+        self.screen_data_2 = r.u8()?;
+        self.attr_data_2 = r.u8()?;
+        self.signals.load_state(r)?;
+        let len = r.u16()?;
+        self.data.clear();
+        for _ in 0..len {
+            self.data.push(r.u32()?);
+        }
+        if self.border > 7 || self.sound_frame >= 200 {
+            return Err(invalid_state("bad ULA border or sound counter"));
+        }
+        if self.col >= self.timings.width || self.row >= self.timings.height {
+            return Err(invalid_state("ULA position outside the frame"));
+        }
+        if self.data.len() != Self::queued(self.col, self.row) {
+            return Err(invalid_state("ULA pixels do not match its position"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,296 @@
+use std::io::{Error, ErrorKind};
+
+use crate::z80::registers::Registers;
+
+use super::memory::{Bank, BANK_SIZE};
+use super::snapshot::{Hardware, Snapshot};
+use super::ula::{TIMINGS_128K, TIMINGS_48K};
+
+const HEADER_LEN: usize = 30;
+/// Length of the version 3 extra header, without the 0x1FFD byte.
+const EXTRA_LEN_V3: usize = 54;
+
+/// .z80 snapshots
+///
+/// Version 1 is a 30 byte header and the 48K RAM, optionally compressed. Versions 2 and 3
+/// (PC = 0 in the first header) add an extra header with the hardware type, paging, AY
+/// registers and T-state counter, followed by one (compressed) block per 16K page.
+impl Snapshot {
+    pub fn from_z80(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < HEADER_LEN {
+            return Err(invalid_data("truncated Z80 header"));
+        }
+        let word = |i: usize| (data[i] as u16) | ((data[i + 1] as u16) << 8);
+
+        let flags = if data[12] == 0xff { 0x01 } else { data[12] };
+
+        let mut regs = Registers::new();
+        regs.a = data[0];
+        regs.f.set(data[1]);
+        regs.set_bc(word(2));
+        regs.set_hl(word(4));
+        regs.pc = word(6);
+        regs.sp = word(8);
+        regs.i = data[10];
+        regs.r = (data[11] & 0x7f) | ((flags & 0x01) << 7);
+        regs.set_de(word(13));
+        regs.set_bc_aux(word(15));
+        regs.set_de_aux(word(17));
+        regs.set_hl_aux(word(19));
+        regs.set_af_aux(((data[21] as u16) << 8) | (data[22] as u16));
+        regs.set_iy(word(23));
+        regs.set_ix(word(25));
+        regs.iff1 = data[27] != 0;
+        regs.iff2 = data[28] != 0;
+        regs.im = data[29] & 0x03;
+        let border = (flags >> 1) & 0x07;
+
+        if regs.pc != 0 {
+            let mut snapshot = Self::new(Hardware::Zx48k, regs);
+            snapshot.border = border;
+
+            let body = &data[HEADER_LEN..];
+            let ram = if flags & 0x20 != 0 {
+                decompress(body, 3 * BANK_SIZE)?
+            } else if body.len() >= 3 * BANK_SIZE {
+                body[..3 * BANK_SIZE].to_vec()
+            } else {
+                return Err(invalid_data("truncated Z80 memory"));
+            };
+            for (bank, chunk) in ram.chunks(BANK_SIZE).enumerate() {
+                snapshot.ram[bank] = chunk.try_into().unwrap();
+            }
+            return Ok(snapshot);
+        }
+
+        if data.len() < HEADER_LEN + 2 {
+            return Err(invalid_data("truncated Z80 header"));
+        }
+        let extra_len = word(30) as usize;
+        let start = HEADER_LEN + 2 + extra_len;
+        if extra_len < 23 || data.len() < start {
+            return Err(invalid_data("truncated Z80 header"));
+        }
+        let v3 = extra_len >= EXTRA_LEN_V3;
+
+        regs.pc = word(32);
+        let hardware = match (v3, data[34]) {
+            (_, 0 | 1) | (true, 3) => Hardware::Zx48k,
+            (false, 3 | 4) | (true, 4 | 5 | 6 | 12) => Hardware::Zx128k,
+            (true, 7 | 8 | 13) => Hardware::Plus3,
+            (true, 9) => Hardware::Pentagon,
+            (_, hardware) => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("unsupported Z80 hardware type {}", hardware),
+                ))
+            }
+        };
+
+        let mut snapshot = Self::new(hardware, regs);
+        snapshot.border = border;
+        if snapshot.is_128k() {
+            snapshot.port_7ffd = data[35];
+        }
+        // on a 48K, bit 2 of the flags tells an AY interface is fitted
+        if snapshot.is_128k() || data[37] & 0x04 != 0 {
+            let mut ay_regs = [0; 16];
+            ay_regs.copy_from_slice(&data[39..55]);
+            snapshot.ay_regs = Some(ay_regs);
+            snapshot.ay_selected = data[38] & 0x0f;
+        }
+        if v3 {
+            let quarter = hardware.frame_ts() / 4;
+            let low = word(55) as u32;
+            let high = data[57] as u32;
+            let ts = (((high + 1) % 4) + 1) * quarter;
+            snapshot.tstates = ts.saturating_sub(low + 1) % hardware.frame_ts();
+        }
+
+        let mut pos = start;
+        while pos + 3 <= data.len() {
+            let len = word(pos) as usize;
+            let page = data[pos + 2];
+            pos += 3;
+
+            let bank: Bank = if len == 0xffff {
+                let raw = data
+                    .get(pos..pos + BANK_SIZE)
+                    .ok_or_else(|| invalid_data("truncated Z80 memory"))?;
+                pos += BANK_SIZE;
+                raw.try_into().unwrap()
+            } else {
+                let packed = data
+                    .get(pos..pos + len)
+                    .ok_or_else(|| invalid_data("truncated Z80 memory"))?;
+                pos += len;
+                decompress(packed, BANK_SIZE)?.try_into().unwrap()
+            };
+
+            let index = if snapshot.is_128k() {
+                (3..=10).contains(&page).then(|| page as usize - 3)
+            } else {
+                match page {
+                    8 => Some(0),
+                    4 => Some(1),
+                    5 => Some(2),
+                    _ => None,
+                }
+            };
+            // ROM pages and interface memory are ignored
+            if let Some(index) = index {
+                snapshot.ram[index] = bank;
+            }
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Saves a version 3 snapshot with compressed pages.
+    pub fn to_z80(&self) -> Vec<u8> {
+        let regs = &self.regs;
+
+        let mut data = Vec::with_capacity(HEADER_LEN + 2 + EXTRA_LEN_V3 + self.ram.len() * 3);
+        data.push(regs.a);
+        data.push(regs.f.get());
+        data.extend_from_slice(&regs.bc().to_le_bytes());
+        data.extend_from_slice(&regs.hl().to_le_bytes());
+        // PC = 0: the real PC is in the extra header
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&regs.sp.to_le_bytes());
+        data.push(regs.i);
+        data.push(regs.r & 0x7f);
+        data.push((regs.r >> 7) | ((self.border & 0x07) << 1));
+        data.extend_from_slice(&regs.de().to_le_bytes());
+        data.extend_from_slice(&regs.bc_aux().to_le_bytes());
+        data.extend_from_slice(&regs.de_aux().to_le_bytes());
+        data.extend_from_slice(&regs.hl_aux().to_le_bytes());
+        data.push(regs.a_alt);
+        data.push(regs.f_alt.get());
+        data.extend_from_slice(&regs.iy().to_le_bytes());
+        data.extend_from_slice(&regs.ix().to_le_bytes());
+        data.push(regs.iff1 as u8);
+        data.push(regs.iff2 as u8);
+        data.push(regs.im & 0x03);
+
+        let mut extra = Vec::with_capacity(EXTRA_LEN_V3);
+        extra.extend_from_slice(&regs.pc.to_le_bytes());
+        extra.push(match self.hardware {
+            Hardware::Zx48k => 0,
+            Hardware::Zx128k => 4,
+            Hardware::Plus3 => 7,
+            Hardware::Pentagon => 9,
+        });
+        extra.push(if self.is_128k() { self.port_7ffd } else { 0 });
+        extra.push(0);
+        extra.push(if !self.is_128k() && self.ay_regs.is_some() {
+            0x04
+        } else {
+            0x00
+        });
+        extra.push(self.ay_selected);
+        extra.extend_from_slice(&self.ay_regs.unwrap_or_default());
+
+        let quarter = self.hardware.frame_ts() / 4;
+        let ts = self.tstates % self.hardware.frame_ts();
+        let low = (quarter - (ts % quarter) - 1) as u16;
+        let high = ((ts / quarter + 3) % 4) as u8;
+        extra.extend_from_slice(&low.to_le_bytes());
+        extra.push(high);
+        // spectator, MGT and Multiface flags, then both halves of the ROM are not RAM
+        extra.extend_from_slice(&[0, 0, 0, 0xff, 0xff]);
+        // joystick keys, MGT type and Disciple buttons are left empty
+        extra.resize(EXTRA_LEN_V3, 0);
+
+        data.extend_from_slice(&(EXTRA_LEN_V3 as u16).to_le_bytes());
+        data.extend_from_slice(&extra);
+
+        let pages: Vec<(u8, &Bank)> = if self.is_128k() {
+            self.ram
+                .iter()
+                .enumerate()
+                .map(|(page, bank)| (page as u8 + 3, bank))
+                .collect()
+        } else {
+            vec![(8, &self.ram[0]), (4, &self.ram[1]), (5, &self.ram[2])]
+        };
+        for (page, bank) in pages {
+            let packed = compress(bank);
+            if packed.len() < BANK_SIZE {
+                data.extend_from_slice(&(packed.len() as u16).to_le_bytes());
+                data.push(page);
+                data.extend_from_slice(&packed);
+            } else {
+                data.extend_from_slice(&[0xff, 0xff, page]);
+                data.extend_from_slice(bank);
+            }
+        }
+
+        data
+    }
+}
+
+impl Hardware {
+    /// T-states per frame.
+    pub fn frame_ts(self) -> u32 {
+        match self {
+            Hardware::Zx48k => TIMINGS_48K.frame_ts() as u32,
+            Hardware::Zx128k | Hardware::Plus3 => TIMINGS_128K.frame_ts() as u32,
+            Hardware::Pentagon => 71680,
+        }
+    }
+}
+
+/// `ED ED nn bb` is `nn` times `bb`, anything else is copied. Stops after `len` bytes.
+fn decompress(data: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < data.len() && out.len() < len {
+        if data[i] == 0xed && data.get(i + 1) == Some(&0xed) && i + 3 < data.len() {
+            out.extend(std::iter::repeat_n(data[i + 3], data[i + 2] as usize));
+            i += 4;
+        } else {
+            out.push(data[i]);
+            i += 1;
+        }
+    }
+    if out.len() < len {
+        return Err(invalid_data("truncated Z80 memory"));
+    }
+    out.truncate(len);
+    Ok(out)
+}
+
+/// Runs of 5 or more bytes (2 or more for 0xED) become `ED ED nn bb`. The byte after a
+/// single 0xED is never part of a run.
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let byte = data[i];
+        let mut run = 1;
+        while i + run < data.len() && data[i + run] == byte && run < 255 {
+            run += 1;
+        }
+
+        if run >= 5 || (byte == 0xed && run >= 2) {
+            out.extend_from_slice(&[0xed, 0xed, run as u8, byte]);
+            i += run;
+        } else if byte == 0xed {
+            out.push(byte);
+            i += 1;
+            if i < data.len() {
+                out.push(data[i]);
+                i += 1;
+            }
+        } else {
+            out.push(byte);
+            i += 1;
+        }
+    }
+    out
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
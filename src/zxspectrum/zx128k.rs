@@ -0,0 +1,177 @@
+use std::io;
+
+use crate::state::{SaveState, StateReader, StateWriter, MACHINE_128K};
+use crate::z80::bus::{Access, Bus};
+
+use super::ay::AY;
+use super::machine::{Model, Spectrum};
+use super::memory::Memory128k;
+use super::snapshot::{Hardware, Snapshot};
+use super::ula::{Timings, TIMINGS_128K, ULA};
+
+pub type Zx128k = Spectrum<Model128k>;
+
+/// 128K paged memory and the AY.
+pub struct Model128k {
+    memory: Memory128k,
+    ay: AY,
+}
+
+impl Model for Model128k {
+    const MACHINE: u8 = MACHINE_128K;
+    const HARDWARE: Hardware = Hardware::Zx128k;
+    const TIMINGS: Timings = TIMINGS_128K;
+    const NAME: &'static str = "Zx128k";
+
+    fn new() -> Self {
+        Self {
+            memory: Memory128k::new(),
+            ay: AY::new(),
+        }
+    }
+
+    fn bus<'a>(&'a mut self, ula: &'a mut ULA) -> impl Bus + 'a {
+        Zx128kBus {
+            memory: &mut self.memory,
+            ula,
+            ay: &mut self.ay,
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        self.memory.read(addr)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        self.memory.write(addr, data)
+    }
+
+    fn screen_read(&self, addr: u16) -> u8 {
+        self.memory.screen_read(addr)
+    }
+
+    fn screen(&self) -> &[u8] {
+        self.memory.page(self.memory.screen_page())
+    }
+
+    fn tick(&mut self, ula: &mut ULA) {
+        self.ay.tick();
+        ula.mix(self.ay.output());
+    }
+
+    fn basic_rom(&self) -> bool {
+        self.memory.rom() == 1
+    }
+
+    fn reset(&mut self) {
+        self.memory.reset();
+        self.ay.reset();
+    }
+
+    fn snapshot(&self, snapshot: &mut Snapshot) {
+        snapshot.ram = (0..8).map(|page| *self.memory.page(page)).collect();
+        snapshot.port_7ffd = self.memory.port_7ffd();
+        snapshot.ay_regs = Some(self.ay.regs());
+        snapshot.ay_selected = self.ay.selected();
+    }
+
+    /// 48K snapshots are restored with the 48K BASIC ROM paged in and paging locked.
+    fn restore(&mut self, snapshot: &Snapshot) {
+        if snapshot.is_128k() {
+            for page in 0..8 {
+                *self.memory.page_mut(page) = snapshot.ram[page];
+            }
+            self.memory.set_port_7ffd(snapshot.port_7ffd);
+        } else {
+            for (page, bank) in [5, 2, 0].into_iter().zip(snapshot.ram.iter()) {
+                *self.memory.page_mut(page) = *bank;
+            }
+            self.memory.set_port_7ffd(0x30);
+        }
+        match snapshot.ay_regs {
+            Some(regs) => self.ay.restore(&regs, snapshot.ay_selected),
+            None => self.ay.reset(),
+        }
+    }
+}
+
+impl SaveState for Model128k {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+        self.ay.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), io::Error> {
+        self.memory.load_state(r)?;
+        self.ay.load_state(r)
+    }
+}
+
+struct Zx128kBus<'a> {
+    memory: &'a mut Memory128k,
+    ula: &'a mut ULA,
+    ay: &'a mut AY,
+}
+
+impl Bus for Zx128kBus<'_> {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.memory.read(addr)
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.memory.write(addr, data)
+    }
+
+    fn io_read(&mut self, port: u16) -> u8 {
+        if port & 0xc002 == 0xc000 {
+            // AY register
+            return self.ay.read();
+        }
+
+        if port & 0x00e0 == 0x0000 {
+            //  Kempston joystick
+            0x00
+        } else if port & 0x0001 == 0x0000 {
+            // ULA
+            self.ula.read_port(port)
+        } else {
+            self.ula.floating_bus()
+        }
+    }
+
+    fn io_write(&mut self, port: u16, data: u8) {
+        if port & 0x0001 == 0x0000 {
+            // ULA
+            self.ula.write_port(port, data);
+        }
+        match port & 0xc002 {
+            0xc000 => self.ay.select(data),
+            0x8000 => self.ay.write(data),
+            _ => (),
+        }
+        if port & 0x8002 == 0x0000 {
+            // Paging
+            self.memory.write_7ffd(data);
+        }
+    }
+
+    fn int(&mut self) -> bool {
+        self.ula.signals.interrupt
+    }
+
+    fn int_ack(&mut self) -> u8 {
+        self.ula.floating_bus()
+    }
+
+    fn wait(&mut self, access: Option<Access>) -> bool {
+        match access {
+            Some(access @ Access::Mem { addr, .. }) => {
+                self.ula.holds_cpu(access, self.memory.is_contended(addr))
+            }
+            Some(access @ Access::Io { port, .. }) => {
+                self.ula.holds_cpu(access, self.memory.is_contended(port))
+            }
+            None => false,
+        }
+    }
+}
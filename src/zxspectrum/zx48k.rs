@@ -1,135 +1,139 @@
-use iced::futures::channel::mpsc::{Receiver, Sender};
-use rfd::FileDialog;
-use tokio::task;
+use std::io;
 
-use std::sync::{mpsc, Arc, Mutex};
-use std::time::Duration;
-use std::{env, fs::File, io::Read};
+use crate::state::{SaveState, StateReader, StateWriter, MACHINE_48K};
+use crate::z80::bus::{Access, Bus};
 
-use crate::signals::SignalReq;
-use crate::z80::cpu::CPU;
-use crate::z80::registers::Registers;
+use super::ay::AY;
+use super::machine::{Model, Spectrum};
+use super::memory::{load_rom, BANK_SIZE};
+use super::snapshot::{Hardware, Snapshot};
+use super::ula::{Timings, TIMINGS_48K, ULA};
 
-use super::tap::Tap;
-use super::ula::ULA;
+pub type Zx48k = Spectrum<Model48k>;
 
-use iced::keyboard::Event as KeyEvent;
-
-#[derive(Debug)]
-pub enum MachineMessage {
-    CPUWait,
-    CPUResume,
-    CPUSetRegisters(Registers),
-    Reset,
-    TapLoad(std::path::PathBuf),
+/// 48K memory, the ROM at 0x0000, and the AY interface when one is plugged.
+pub struct Model48k {
+    memory: [[u8; 0x4000]; 4],
+    ay: Option<AY>,
 }
 
-#[derive(Debug)]
-enum TapState {
-    Empty,
-    Loading,
-    Ready,
+impl Zx48k {
+    /// Plugs (or unplugs) a Melodik-style AY interface on ports 0xFFFD/0xBFFD.
+    pub fn set_melodik(&mut self, enabled: bool) {
+        self.model_mut().ay = if enabled { Some(AY::new()) } else { None };
+    }
 }
 
-pub struct Zx48k {
-    memory: [[u8; 0x4000]; 4],
+impl Model for Model48k {
+    const MACHINE: u8 = MACHINE_48K;
+    const HARDWARE: Hardware = Hardware::Zx48k;
+    const TIMINGS: Timings = TIMINGS_48K;
+    const NAME: &'static str = "Zx48k";
 
-    cpu: CPU,
-    ula: ULA,
+    fn new() -> Self {
+        Self {
+            memory: [load_rom("48.rom"), [0; 0x4000], [0; 0x4000], [0; 0x4000]],
+            ay: None,
+        }
+    }
 
-    tap: Option<Tap>,
-    tap_state: TapState,
+    fn bus<'a>(&'a mut self, ula: &'a mut ULA) -> impl Bus + 'a {
+        Zx48kBus {
+            memory: &mut self.memory,
+            ula,
+            ay: self.ay.as_mut(),
+        }
+    }
 
-    machine_ctl_rx: Receiver<MachineMessage>,
-    machine_ctl_tx: Sender<MachineMessage>,
-}
+    fn read(&self, addr: u16) -> u8 {
+        self.memory[addr as usize >> 14][addr as usize & 0x3fff]
+    }
 
-// todo: review, and move out
-#[derive(Debug)]
-pub enum UICommands {
-    DrawBuffer(usize),
-}
+    fn write(&mut self, addr: u16, data: u8) {
+        if addr >= 0x4000 {
+            self.memory[addr as usize >> 14][addr as usize & 0x3fff] = data;
+        }
+    }
 
-impl Zx48k {
-    pub fn new(
-        bitmaps: [Arc<Mutex<Vec<u8>>>; 2],
-        event_rx: Receiver<KeyEvent>,
-        machine_ctl_rx: Receiver<MachineMessage>,
-        machine_ctl_tx: Sender<MachineMessage>,
-        ui_ctl_tx: Sender<UICommands>,
-        sound_tx: mpsc::Sender<f32>,
-    ) -> Self {
-        Self {
-            memory: [load_rom(), [0; 0x4000], [0; 0x4000], [0; 0x4000]],
-            cpu: CPU::new(),
-            ula: ULA::new(bitmaps, event_rx, ui_ctl_tx.clone(), sound_tx),
-            machine_ctl_rx,
-            machine_ctl_tx,
-            tap: None,
-            tap_state: TapState::Empty,
+    fn screen_read(&self, addr: u16) -> u8 {
+        self.read(addr)
+    }
+
+    fn screen(&self) -> &[u8] {
+        &self.memory[1]
+    }
+
+    fn tick(&mut self, ula: &mut ULA) {
+        if let Some(ay) = self.ay.as_mut() {
+            ay.tick();
+            ula.mix(ay.output());
         }
     }
 
-    pub async fn run(self: &mut Self) -> ! {
-        println!("Zx48k::run()");
-        let mut interval = tokio::time::interval(Duration::from_millis(20));
-        loop {
-            interval.tick().await;
-            // let t = std::time::Instant::now();
-            for _ in 0..(3_500_000 / 50) {
-                self.ula.tick();
-                self.bus_tick();
-                self.ula.tick();
-                self.bus_tick();
-                if !(self.ula.content && (self.cpu.signals.addr & 0xc000 == 0x4000)) {
-                    let trap = self.cpu.tick();
-                    self.bus_tick();
+    fn basic_rom(&self) -> bool {
+        true
+    }
 
-                    match trap {
-                        Some(0x056B) => {
-                            // println!("Trap 0x056B - load tap block - {:?}", self.tap_state);
-                            self.ula.clean_keyboard();
+    fn reset(&mut self) {
+        if let Some(ay) = self.ay.as_mut() {
+            ay.reset();
+        }
+    }
 
-                            match self.tap_state {
-                                TapState::Empty => {
-                                    self.tap_state = TapState::Loading;
-                                    load_tap_file(self.machine_ctl_tx.clone());
-                                }
-                                TapState::Loading => (),
-                                TapState::Ready => self.load_tap_block(),
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-            }
+    fn snapshot(&self, snapshot: &mut Snapshot) {
+        snapshot.ram = self.memory[1..].to_vec();
+        if let Some(ay) = self.ay.as_ref() {
+            snapshot.ay_regs = Some(ay.regs());
+            snapshot.ay_selected = ay.selected();
+        }
+    }
 
-            match self.machine_ctl_rx.try_next() {
-                Ok(msg) => match msg {
-                    Some(MachineMessage::CPUWait) => self.cpu.wait = true,
-                    Some(MachineMessage::CPUResume) => self.cpu.wait = false,
-                    Some(MachineMessage::Reset) => self.reset(),
-                    Some(MachineMessage::CPUSetRegisters(_)) => todo!(),
-                    Some(MachineMessage::TapLoad(file)) => {
-                        self.tap = Some(Tap::new(&file).unwrap());
-                        self.tap_state = TapState::Ready;
-                    }
-                    None => (),
-                    // _ => unreachable!("Invalid machine message"),
-                },
-                Err(_) => {}
-            }
-            // println!("t: {}ms", t.elapsed().as_millis());
+    /// 128K snapshots only keep the pages mapped at the moment they were taken.
+    fn restore(&mut self, snapshot: &Snapshot) {
+        for (bank, addr) in [0x4000, 0x8000, 0xc000].into_iter().enumerate() {
+            self.memory[bank + 1] = *snapshot.bank(addr);
+        }
+        if let (Some(ay), Some(regs)) = (self.ay.as_mut(), snapshot.ay_regs) {
+            ay.restore(&regs, snapshot.ay_selected);
         }
     }
+}
 
-    fn reset(self: &mut Self) {
-        self.cpu.do_reset = true;
-        self.tap = None;
-        self.tap_state = TapState::Empty;
+/// The ROM is not saved, it is loaded from `bin/`.
+impl SaveState for Model48k {
+    fn save_state(&self, w: &mut StateWriter) {
+        for bank in &self.memory[1..] {
+            w.raw(bank);
+        }
+        w.bool(self.ay.is_some());
+        if let Some(ay) = self.ay.as_ref() {
+            ay.save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), io::Error> {
+        for bank in self.memory[1..].iter_mut() {
+            bank.copy_from_slice(r.raw(BANK_SIZE)?);
+        }
+        self.ay = if r.bool()? {
+            let mut ay = AY::new();
+            ay.load_state(r)?;
+            Some(ay)
+        } else {
+            None
+        };
+        Ok(())
     }
+}
 
-    fn mem_read(self: &mut Self, addr: u16) -> u8 {
+struct Zx48kBus<'a> {
+    memory: &'a mut [[u8; 0x4000]; 4],
+    ula: &'a mut ULA,
+    ay: Option<&'a mut AY>,
+}
+
+impl Bus for Zx48kBus<'_> {
+    fn mem_read(&mut self, addr: u16) -> u8 {
         let bank: usize = (addr >> 14) as usize;
         let addr = (addr & 0x3fff) as usize;
         let data = self.memory[bank][addr];
@@ -137,7 +141,7 @@ impl Zx48k {
         data
     }
 
-    fn mem_write(self: &mut Self, addr: u16, data: u8) {
+    fn mem_write(&mut self, addr: u16, data: u8) {
         let bank = (addr >> 14) as usize;
         let addr = (addr & 0x3fff) as usize;
         if bank != 0 {
@@ -146,129 +150,56 @@ impl Zx48k {
         }
     }
 
-    fn bus_tick(self: &mut Self) {
-        match self.cpu.signals.mem {
-            SignalReq::Read => self.cpu.signals.data = self.mem_read(self.cpu.signals.addr),
-            SignalReq::Write => self.mem_write(self.cpu.signals.addr, self.cpu.signals.data),
-            SignalReq::None => (),
-        }
-
-        match self.ula.signals.mem {
-            SignalReq::Read => self.ula.signals.data = self.mem_read(self.ula.signals.addr),
-            SignalReq::Write => self.mem_write(self.ula.signals.addr, self.ula.signals.data),
-            SignalReq::None => (),
+    fn io_read(&mut self, port: u16) -> u8 {
+        if let (Some(ay), 0xc000) = (self.ay.as_mut(), port & 0xc002) {
+            return ay.read();
         }
 
-        match self.cpu.signals.port {
-            SignalReq::Read => {
-                if self.cpu.signals.addr & 0x00e0 == 0x0000 {
-                    //  Kempston joystick
-                    self.cpu.signals.data = 0x00;
-                } else if self.cpu.signals.addr & 0x0001 == 0x0000 {
-                    // ULA
-                    self.cpu.signals.data = self.ula.read_port(self.cpu.signals.addr);
-                } else {
-                    self.cpu.signals.data = 0xff;
-                    // println!(
-                    //     "port read - {:04x} ({:016b}) - pc: {:04x}",
-                    //     self.cpu.signals.addr, self.cpu.signals.addr, self.cpu.regs.pc
-                    // );
-                }
-            }
-            SignalReq::Write => {
-                if self.cpu.signals.addr & 0x0001 == 0x0000 {
-                    // ULA
-                    self.ula
-                        .write_port(self.cpu.signals.addr, self.cpu.signals.data);
-                } else {
-                    // println!(
-                    //     "port write - {:04x} ({:016b}) - pc: {:04x}",
-                    //     self.cpu.signals.addr, self.cpu.signals.addr, self.cpu.regs.pc
-                    // );
-                }
-            }
-            SignalReq::None => (),
+        if port & 0x00e0 == 0x0000 {
+            //  Kempston joystick
+            0x00
+        } else if port & 0x0001 == 0x0000 {
+            // ULA
+            self.ula.read_port(port)
+        } else {
+            self.ula.floating_bus()
         }
-        self.cpu.signals.interrupt = self.ula.signals.interrupt;
     }
 
-    fn load_tap_block(&mut self) {
-        let data: Vec<u8> = match self.tap.as_mut() {
-            Some(tap) => tap
-                .next_block()
-                .map(|block| block.to_vec())
-                .unwrap_or_else(Vec::new),
-            None => {
-                println!("TAP file not loaded, returning empty vector");
-                Vec::new()
+    fn io_write(&mut self, port: u16, data: u8) {
+        if let Some(ay) = self.ay.as_mut() {
+            match port & 0xc002 {
+                0xc000 => ay.select(data),
+                0x8000 => ay.write(data),
+                _ => (),
             }
-        };
-        if data.is_empty() {
-            return;
         }
 
-        let requested_length = self.cpu.regs.de();
-        let start_address = self.cpu.regs.ix();
-        println!("Loading block to {:04x} ({})", start_address, data.len());
-
-        self.cpu.wait = true;
-        let a = data[0];
-        if self.cpu.regs.a_alt == a {
-            if self.cpu.regs.f_alt.c {
-                let mut checksum = data[0];
-                for i in 0..(requested_length as usize) {
-                    let loaded_byte = data[i + 1];
-                    self.mem_write(start_address.wrapping_add(i as u16), loaded_byte);
-                    checksum ^= loaded_byte;
-                }
-
-                if start_address == 0x4000 {}
-
-                println!(
-                    "{} == {} : {}",
-                    checksum,
-                    data[requested_length as usize + 1],
-                    checksum == data[requested_length as usize + 1]
-                );
-                self.cpu.regs.f.c = true;
-            } else {
-                self.cpu.regs.f.c = true;
-            }
-            println!("done");
+        if port & 0x0001 == 0x0000 {
+            // ULA
+            self.ula.write_port(port, data);
         } else {
-            self.cpu.regs.f.c = false;
-            println!("BAD Block");
+            // println!("port write - {:04x} ({:016b})", port, port);
         }
-
-        self.cpu.regs.pc = 0x05e2;
-        self.cpu.wait = false;
     }
-}
 
-fn load_tap_file(mut machine_ctl_tx: Sender<MachineMessage>) {
-    let _ = task::spawn(async move {
-        let path: std::path::PathBuf = env::current_dir().unwrap();
-        let file: Option<_> = FileDialog::new()
-            .add_filter("tap", &["tap"])
-            .set_directory(path)
-            .pick_file();
-        match file {
-            Some(f) => machine_ctl_tx
-                .start_send(MachineMessage::TapLoad(f))
-                .unwrap(),
-            None => machine_ctl_tx.start_send(MachineMessage::Reset).unwrap(),
-        }
-    });
-}
-
-fn load_rom() -> [u8; 0x4000] {
-    let mut path = env::current_dir().unwrap().join("bin");
-    // path = path.join("DiagROMv.171.rom");
-    path = path.join("48.rom");
+    fn int(&mut self) -> bool {
+        self.ula.signals.interrupt
+    }
 
-    let mut f = File::open(&path).expect("Failed to open ROM file");
-    let mut rom = [0; 0x4000];
-    f.read_exact(&mut rom).expect("Failed to read ROM file");
+    fn int_ack(&mut self) -> u8 {
+        self.ula.floating_bus()
+    }
 
-    rom
+    fn wait(&mut self, access: Option<Access>) -> bool {
+        match access {
+            Some(access @ Access::Mem { addr, .. }) => {
+                self.ula.holds_cpu(access, addr & 0xc000 == 0x4000)
+            }
+            Some(access @ Access::Io { port, .. }) => {
+                self.ula.holds_cpu(access, port & 0xc000 == 0x4000)
+            }
+            None => false,
+        }
+    }
 }